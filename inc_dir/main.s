@@ -0,0 +1,5 @@
+; includes resolved next to this file
+        .org $2000
+main    .inc "sub.s"
+        .incbin "data.bin"
+        .byte filesize("data.bin")
@@ -0,0 +1,2 @@
+; included by sub.s, relative to sub.s
+deep    .byte $42
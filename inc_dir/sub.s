@@ -0,0 +1,3 @@
+; included by main.s
+sub     .word main
+        .inc "nested/deep.s"
@@ -20,16 +20,38 @@ pub trait Action {
         false
     }
 
+    /// Return a flag for if this is a 6502 instruction.
+    fn is_opcode(&self) -> bool {
+        false
+    }
+
     /// Return a flag for if this is an ".else" or ".endif" pseudo-op.
     fn is_if_affiliated(&self) -> bool {
         false
     }
 
+    /// Return a flag for if this is an ".endenum" pseudo-op.
+    fn is_enum_affiliated(&self) -> bool {
+        false
+    }
+
+    /// Return a flag for if this belongs in a `.struct`: reserving space, defining a symbol,
+    /// or a macro whose lines do.
+    fn is_struct_affiliated(&self) -> bool {
+        false
+    }
+
     /// If this is the start of a new macro, return the name of the macro.
-    fn is_macro_def(&self) -> Option<String> {
+    fn is_macro_def(&self) -> Option<Rc<LineSlice>> {
         None
     }
 
+    /// Return how many fill bytes to place before this action (and its label), to align it.
+    fn align_pad(&self, assembler: &mut Assembler) -> u16 {
+        let _ = assembler;
+        0
+    }
+
     /// If this is an equ macro, try to evaluate it.
     fn eval_equ(&self, assembler: &mut Assembler) -> Option<u16> {
         let _ = assembler;
@@ -1,20 +1,25 @@
 //! Assembler struct stuff.
 
 use std::{
-    collections::{hash_map, HashMap},
+    collections::{hash_map, HashMap, HashSet},
     mem,
     rc::Rc,
 };
 
 use crate::{
-    mac::{end_macro, Macro},
-    parse::ParsedLine,
-    source::{Line, LineSlice, Source, SrcStack},
+    expr::Func,
+    mac::{end_macro, start_macro, Macro},
+    opcode::{cycles, disassemble, Cpu},
+    parse::{is_alpha, is_alphanum, ParsedLine},
+    source::{self, FileResolver, FsResolver, Line, LineSlice, Source, SrcStack},
     symbol::Symbol,
 };
 
-/// Indicates how many bytes should be printed on a listing line.
-const BYTES_PER_LINE: usize = 3;
+/// Indicates how many bytes should be printed on a listing line, unless set otherwise.
+pub const BYTES_PER_LINE: usize = 3;
+
+/// The width of the listing's disassembly column, when there is one.
+const DISASM_WIDTH: usize = 18;
 
 /// Represents the current assembly pass.
 #[derive(PartialEq)]
@@ -24,22 +29,171 @@ pub enum Pass {
     Pass2,
 }
 
+/// An error or warning issued during assembly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    /// The printed message, which starts with the source position if there is one.
+    pub message: String,
+    /// Set if this is only a warning.
+    pub warning: bool,
+}
+
+/// An open `.if`, with its `.elif`s and `.else`.
+pub struct Cond {
+    /// Set if the lines in the current branch are assembled.
+    pub active: bool,
+    /// Set once a branch of the chain has been taken, or if the whole chain is skipped.
+    pub taken: bool,
+    /// The opening `.if`.
+    pub slice: Rc<LineSlice>,
+}
+
+/// A `.segment`: a named part of the output, with its own PC.
+struct Section {
+    name: String,
+    /// The PC, saved while another segment is active.
+    pc: u16,
+    /// The output bytes, split into contiguous regions with their load addresses.
+    regions: Vec<(u16, Vec<u8>)>,
+}
+
+impl Section {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            pc: DEFAULT_PC,
+            regions: Vec::new(),
+        }
+    }
+}
+
 pub struct Assembler {
     pub src_stk: Box<SrcStack>,
+    /// Reads included files.
+    pub resolver: Rc<dyn FileResolver>,
     parsed_lines: Vec<ParsedLine>,
-    pub debug_str: String,
-    pub debug_fmt: Option<String>,
+    /// The index in the parsed lines of the line pass2 is assembling.
+    line_index: usize,
+    /// The output of each debug stream, by name (the default stream is named "").
+    pub debug_str: HashMap<String, String>,
+    /// The format string of each active debug stream, by name.
+    pub debug_fmt: HashMap<String, String>,
+    /// The label defined on the current line, with its value and comment, waiting for the
+    /// statement's size before it's output to the debug string.
+    debug_pending: Option<(String, Rc<LineSlice>, u16, Option<String>)>,
     pub pass: Pass,
     pub symtab: HashMap<String, Box<Symbol>>,
-    pub pc: u16,
+    /// The *Program Counter*; read it with `pc()`, the same value `*` evaluates to.
+    pc: u16,
     pub cur_line: Option<Rc<Line>>,
     building_comment: Option<String>,
+    /// Set if a bare `;` line is a paragraph break in `building_comment`, rather than ending
+    /// it, as set by `.docparas`.
+    pub doc_paras: bool,
+    /// Set after a bare `;` line under `.docparas`, until the next comment line.
+    comment_break: bool,
+    /// Set while inside a `/* ... */` comment, which can span lines.
+    in_block_comment: bool,
     errcount: u32,
     pub output_flag: bool,
-    pub if_stack: Vec<bool>,
+    /// Set if labels defined while `output_flag` is clear are left out of the debug streams,
+    /// as set by `.dbgoutput`.
+    pub dbg_output_only: bool,
+    pub if_stack: Vec<Cond>,
+    /// For each open `.pushpc`, the PC before it and the logical PC it set.
+    pub pc_stack: Vec<(u16, u16)>,
+    /// For each open `.pushorg`, the active `.segment` and the PC before it.
+    org_stack: Vec<(usize, u16)>,
+    /// The canonical path of each file that's been through a `.once`.
+    pub once_files: HashSet<String>,
+    /// The PC at the open `.page`, if any.
+    pub page_start: Option<u16>,
+    /// The name of the open `.struct` and the PC before it, if any.
+    pub struct_def: Option<(Rc<LineSlice>, u16)>,
+    /// The names of the open `.scope`s, outermost first.
+    pub scopes: Vec<String>,
+    /// The value of the next name in the open `.enum`, if any.
+    pub enum_next: Option<u16>,
+    /// Set if the last bytes ended at `$FFFF`, so the PC wrapped to `$0000`.
+    pub pc_wrapped: bool,
+    /// The address code must stay under, as set by `.limit`.
+    pub pc_limit: Option<u16>,
+    /// The next free zero-page address for `.zpvar`.
+    pub zp_next: u16,
+    /// The output bytes, split into contiguous regions with their load addresses.
+    pub segments: Vec<(u16, Vec<u8>)>,
+    /// Every `.segment` in order of first use, starting with the default one.
+    sections: Vec<Section>,
+    /// The index of the active `.segment`.
+    cur_section: usize,
     pub listing: Option<Vec<String>>,
+    /// Set if the listing shows each instruction's bytes disassembled.
+    pub list_disasm: bool,
+    /// How many bytes are printed on a listing line; more go on continuation lines.
+    pub list_bytes: usize,
     listing_index: Option<Vec<usize>>,
+    /// The listing's title and subtitle, as set by `.title` and `.subttl`.
+    pub list_title: Option<String>,
+    pub list_subtitle: Option<String>,
+    /// The path of the last line added to the listing.
+    listing_path: Option<Rc<str>>,
     pub macros: HashMap<String, Rc<Macro>>,
+    /// Every `.func` defined so far, by name.
+    pub funcs: HashMap<String, Rc<Func>>,
+    /// The parameters of the `.func` whose body is being parsed.
+    pub func_params: Option<Vec<String>>,
+    /// The argument values of each `.func` call being evaluated, innermost last.
+    pub func_args: Vec<Vec<u32>>,
+    pub cold_start: Option<u16>,
+    pub warm_start: Option<u16>,
+    pub warnings: Vec<String>,
+    /// Set if a warning repeated from the same source line is only reported once, as set by
+    /// `.warnonce`.
+    pub warn_once: bool,
+    /// For each warning reported once, by its message and the position of its source line
+    /// (the macro body line, for an expanded one): its index in `warnings` and `diagnostics`,
+    /// and how many times it was issued.
+    warn_repeats: HashMap<(String, String), (usize, usize, u32)>,
+    /// Every error and warning issued so far.
+    pub diagnostics: Vec<AsmError>,
+    /// The address and base cycle count of each instruction assembled in pass2.
+    pub cycle_log: Vec<(u16, u32)>,
+    /// The address ranges to report the cycle counts of at the end of pass2, as set by
+    /// `.cycles`: the start, the end (exclusive), and the directive's position.
+    pub cycle_spans: Vec<(u16, u16, Rc<LineSlice>)>,
+    /// The report of each `.cycles` range.
+    pub cycle_reports: Vec<String>,
+    pub even_words: bool,
+    /// Set if `.word` places its words big endian, as set by `.endian`.
+    pub big_endian: bool,
+    pub warn_page: bool,
+    /// Set if a warning is issued for `jmp ($xxFF)` on the NMOS 6502, as set by
+    /// `.warn-jmpbug`.
+    pub warn_jmp_bug: bool,
+    pub auto_branch: bool,
+    /// Set if every conditional branch is assembled as an inverse branch around a `jmp`, as
+    /// set by `.longbranch`.
+    pub long_branches: bool,
+    /// Set if other assemblers' synonyms for instructions (`blt` for `bcc`, ...) are
+    /// recognized, as set by `.aliases`.
+    pub aliases: bool,
+    /// Set if `Abs` addressing modes may be narrowed to zero-page ones.
+    pub zp_opt: bool,
+    /// The CPU whose instructions are available, as set by `.cpu`.
+    pub cpu: Cpu,
+    /// The value `cpu` starts each pass with.
+    pub cpu_default: Cpu,
+    /// The value `zp_opt` starts each pass with.
+    pub zp_opt_default: bool,
+    /// Set if expressions should be parsed with signed comparisons/division.
+    pub signed: bool,
+    /// Lines of symbol table dumps to predefine symbols from.
+    pub imports: Vec<Rc<Line>>,
+    /// The paths of the files of `{name}={expr}` lines to predefine symbols from.
+    pub define_files: Vec<String>,
+    /// The size to pad the output to, with `rom_fill` bytes, if any.
+    pub rom_size: Option<usize>,
+    pub rom_fill: u8,
 }
 
 /// The initial value of the assembler's program counter.
@@ -56,27 +210,84 @@ impl Assembler {
         };
         Self {
             macros: HashMap::new(),
+            funcs: HashMap::new(),
+            func_params: None,
+            func_args: Vec::new(),
             src_stk: Box::new(SrcStack::new(src)),
+            resolver: Rc::new(FsResolver),
             symtab: HashMap::new(),
             pc: DEFAULT_PC,
             parsed_lines: Vec::new(),
+            line_index: 0,
             pass: Pass::None,
             cur_line: None,
-            debug_str: String::new(),
-            debug_fmt: None,
+            debug_str: HashMap::new(),
+            debug_fmt: HashMap::new(),
+            debug_pending: None,
             building_comment: None,
+            doc_paras: false,
+            comment_break: false,
+            in_block_comment: false,
             errcount: 0,
             output_flag: true,
+            dbg_output_only: false,
             if_stack: Vec::new(),
+            pc_stack: Vec::new(),
+            org_stack: Vec::new(),
+            once_files: HashSet::new(),
+            page_start: None,
+            zp_next: 0,
+            struct_def: None,
+            scopes: Vec::new(),
+            enum_next: None,
+            pc_wrapped: false,
+            pc_limit: None,
+            segments: Vec::new(),
+            sections: vec![Section::new(String::new())],
+            cur_section: 0,
             listing,
+            list_disasm: false,
+            list_bytes: BYTES_PER_LINE,
             listing_index,
+            list_title: None,
+            list_subtitle: None,
+            listing_path: None,
+            cold_start: None,
+            warm_start: None,
+            warnings: Vec::new(),
+            warn_once: false,
+            warn_repeats: HashMap::new(),
+            diagnostics: Vec::new(),
+            cycle_log: Vec::new(),
+            cycle_spans: Vec::new(),
+            cycle_reports: Vec::new(),
+            even_words: false,
+            big_endian: false,
+            warn_page: false,
+            warn_jmp_bug: false,
+            auto_branch: false,
+            long_branches: false,
+            aliases: false,
+            zp_opt: true,
+            zp_opt_default: true,
+            cpu: Cpu::Mos6502,
+            cpu_default: Cpu::Mos6502,
+            signed: false,
+            imports: Vec::new(),
+            define_files: Vec::new(),
+            rom_size: None,
+            rom_fill: 0xFF,
         }
     }
 
     /// Run pass1 for a single line.
     fn pass1_line(&mut self, line: Rc<Line>) -> Result<(), String> {
         self.cur_line = Some(line.clone());
-        let parsed = self.parse_line(line.clone())?;
+        self.debug_pending = None;
+        let stripped = self.strip_block_comments(line.clone());
+        let mut parsed = self.parse_line(stripped)?;
+        // The listing shows the line as written.
+        parsed.line = line.clone();
 
         let is_equ = {
             if let Some(action) = &parsed.action {
@@ -94,14 +305,26 @@ impl Assembler {
             }
         };
 
+        let disasm_column = self.disasm_column(None);
         if let Some(listing) = self.listing.as_mut() {
+            if self.listing_path.as_ref() != Some(&line.path) {
+                if self.listing_path.is_some() {
+                    listing.push(format!("====== {}", line.path));
+                }
+                self.listing_path = Some(line.path.clone());
+            }
             listing.push(format!(
-                "{:06} {:04X}        {}",
-                line.line_num, listing_pc, line.text
+                "{:06} {:04X} {:width$} {}{}",
+                line.line_num,
+                listing_pc,
+                "",
+                disasm_column,
+                line.text,
+                width = self.list_bytes * 2
             ));
         }
 
-        if !*self.if_stack.last().unwrap_or(&true) {
+        if self.if_stack.last().is_some_and(|cond| !cond.active) {
             if let Some(action) = &parsed.action {
                 if !action.is_if_affiliated() {
                     return Ok(());
@@ -111,6 +334,23 @@ impl Assembler {
             }
         }
 
+        if self.enum_next.is_some() {
+            // Enum lines are handled entirely here, and don't go on to pass2.
+            return self.pass1_enum_line(&parsed);
+        }
+
+        if let Some(action) = &parsed.action {
+            if self.struct_def.is_some() && !action.is_struct_affiliated() {
+                // Anything else would be silently dropped, as nothing is output.
+                return action.line_slice().err(
+                    "only labels, '=', '.byte'/'.word' without values, '.ds'/'.res'/'.space', \
+                     conditionals and macros are allowed in '.struct'",
+                );
+            }
+            let pad = action.align_pad(self);
+            self.pc = self.pc.wrapping_add(pad);
+        }
+
         let comment = parsed.filter_comment();
         if let Some(label_slice) = &parsed.label {
             let comment_label = {
@@ -120,17 +360,37 @@ impl Assembler {
                     comment.map(String::from)
                 }
             };
-            if !is_equ {
+            if let Some((name, _)) = &self.struct_def {
+                // A struct member is defined to its offset in the struct.
+                let member = format!("{}::{}", name.text(), label_slice.text());
+                self.def_symbol(&member, label_slice.clone(), self.pc)?;
+            } else if !is_equ {
                 self.def_label(label_slice.text(), label_slice.clone(), comment_label)?;
             }
         }
+        let mut size = 0;
         if let Some(action) = &parsed.action {
-            if let Some(name) = action.is_macro_def() {
-                let mut mac = Macro::new();
+            if let Some(name_slice) = action.is_macro_def() {
+                // The name was parsed as an expression, but isn't a symbol.
+                self.forget_ref(&name_slice);
+                let name = name_slice.text().to_string();
+                // The macro is documented like a label would be.
+                let comment_mac = self
+                    .building_comment
+                    .take()
+                    .or_else(|| comment.map(String::from));
+                let mut mac = Macro::new(comment_mac);
+                // Nested definitions have their own '.endm's.
+                let mut depth = 0;
                 for line in self.src_stk.by_ref() {
                     mac.add_line(line.clone());
-                    if end_macro(&line) {
-                        break;
+                    if start_macro(&line) {
+                        depth += 1;
+                    } else if end_macro(&line) {
+                        if depth == 0 {
+                            break;
+                        }
+                        depth -= 1;
                     }
                 }
                 if let hash_map::Entry::Vacant(e) = self.macros.entry(name.clone()) {
@@ -141,30 +401,177 @@ impl Assembler {
                         .err(&format!("macro {} redefined", name))?;
                 }
             } else {
-                let size = action.pass1(self, parsed.label.clone())?;
+                let pc = self.pc;
+                size = action.pass1(self, parsed.label.clone())?;
+                if self.pc != pc {
+                    // The PC was set explicitly, so a wrap was intended.
+                    self.pc_wrapped = false;
+                }
+                if size > 0 && self.struct_def.is_none() {
+                    self.check_pc_range(&action.line_slice(), size)?;
+                }
                 self.pc = self.pc.wrapping_add(size);
             }
         }
+        if let Some((label, slice, value, comment)) = self.debug_pending.take() {
+            self.debug_label(&label, slice, value, size, comment.as_deref())?;
+        }
+        let comment_only = parsed.label.is_none() && parsed.action.is_none();
         if let Some(c) = comment {
-            if parsed.label.is_none() && parsed.action.is_none() {
+            if comment_only {
                 let s = self.building_comment.get_or_insert_with(String::new);
+                if mem::take(&mut self.comment_break) {
+                    s.push('\n');
+                }
                 s.push_str(c);
                 s.push('\n');
             }
         }
 
-        if comment.is_none() || parsed.label.is_some() || parsed.action.is_some() {
+        let bare_comment = comment_only && comment.is_none() && parsed.comment.is_some();
+        if bare_comment && self.doc_paras && self.building_comment.is_some() {
+            // A blank line is only added if another comment line follows.
+            self.comment_break = true;
+        } else if comment.is_none() || !comment_only {
             self.building_comment = None;
+            self.comment_break = false;
         }
 
         if let Some(index) = self.listing_index.as_mut() {
             index.push(self.listing.as_ref().unwrap().len() - 1);
         }
+        parsed.size = size;
         self.parsed_lines.push(parsed);
 
         Ok(())
     }
 
+    /// Blank out any `/* ... */` comments in the line, returning it unchanged if there are
+    /// none. Blanking keeps the columns of everything else the same.
+    fn strip_block_comments(&mut self, line: Rc<Line>) -> Rc<Line> {
+        if !self.in_block_comment && !line.text.contains("/*") {
+            return line;
+        }
+        let mut text = String::with_capacity(line.text.len());
+        let mut changed = false;
+        let mut quote: Option<char> = None;
+        let mut chars = line.text.chars().peekable();
+        while let Some(c) = chars.next() {
+            if self.in_block_comment {
+                if c == '*' && chars.peek() == Some(&'/') {
+                    chars.next();
+                    text.push(' ');
+                    self.in_block_comment = false;
+                }
+                text.push(' ');
+                changed = true;
+                continue;
+            }
+            if let Some(q) = quote {
+                if c == '\\' {
+                    // Keep an escaped quote from ending the string.
+                    text.push(c);
+                    if let Some(c) = chars.next() {
+                        text.push(c);
+                    }
+                    continue;
+                } else if c == q {
+                    quote = None;
+                }
+            } else {
+                match c {
+                    '\'' | '"' => quote = Some(c),
+                    ';' => {
+                        text.push(c);
+                        text.extend(chars);
+                        break;
+                    }
+                    '/' if chars.peek() == Some(&'/') => {
+                        text.push(c);
+                        text.extend(chars);
+                        break;
+                    }
+                    '/' if chars.peek() == Some(&'*') => {
+                        chars.next();
+                        text.push_str("  ");
+                        self.in_block_comment = true;
+                        changed = true;
+                        continue;
+                    }
+                    _ => (),
+                }
+            }
+            text.push(c);
+        }
+        if changed {
+            Rc::new(Line::with_path(text, line.path.clone(), line.line_num))
+        } else {
+            line
+        }
+    }
+
+    /// Return the *Program Counter*: the address of the current line, as `*` evaluates to.
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    /// Set the *Program Counter*, as `.org` does.
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    /// Check that `size` bytes at the PC don't run past `$FFFF` or the `.limit`.
+    fn check_pc_range(&mut self, slice: &LineSlice, size: u16) -> Result<(), String> {
+        let start = self.pc;
+        let end = start as u32 + size as u32;
+        if self.pc_wrapped || end > 0x10000 {
+            return slice.err(&format!(
+                "program counter ran past $FFFF (at ${:04X}); use '.org' to wrap around",
+                start
+            ));
+        }
+        if let Some(limit) = self.pc_limit {
+            if end > limit as u32 {
+                return slice.err(&format!(
+                    "${:04X}-${:04X} runs past the limit of ${:04X}",
+                    start,
+                    end - 1,
+                    limit
+                ));
+            }
+        }
+        // Ending exactly at $FFFF is fine, as long as nothing follows.
+        self.pc_wrapped = end == 0x10000;
+        Ok(())
+    }
+
+    /// Run pass1 for a line inside an `.enum`: each label is defined to the next value, unless
+    /// it's given one with `=`.
+    fn pass1_enum_line(&mut self, parsed: &ParsedLine) -> Result<(), String> {
+        match (&parsed.label, &parsed.action) {
+            (_, Some(action)) if action.is_enum_affiliated() => {
+                action.pass1(self, parsed.label.clone())?;
+            }
+            (Some(label), Some(action)) if action.is_equ() => {
+                action.pass1(self, Some(label.clone()))?;
+                let value = self.symtab[&self.scoped_name(label.text())].value.unwrap();
+                self.enum_next = Some(value.wrapping_add(1));
+            }
+            (Some(label), None) => {
+                let value = self.enum_next.unwrap();
+                self.def_symbol(label.text(), label.clone(), value)?;
+                self.enum_next = Some(value.wrapping_add(1));
+            }
+            (_, Some(action)) => {
+                return action
+                    .line_slice()
+                    .err("only names and '=' are allowed in '.enum'");
+            }
+            (None, None) => (),
+        }
+        Ok(())
+    }
+
     /// Read the entire source, constructing the symbol table.
     pub fn pass1(&mut self) -> Result<(), String> {
         self.pass = Pass::Pass1;
@@ -172,17 +579,100 @@ impl Assembler {
         self.symtab.clear();
         self.pc = DEFAULT_PC;
         self.if_stack.clear();
+        self.once_files.clear();
+        self.zp_next = 0;
+        self.enum_next = None;
+        self.in_block_comment = false;
+        self.doc_paras = false;
+        self.comment_break = false;
+        self.pc_wrapped = false;
+        self.pc_limit = None;
+        self.pc_stack.clear();
+        self.org_stack.clear();
+        self.reset_sections();
+        self.page_start = None;
+        self.list_title = None;
+        self.list_subtitle = None;
+        self.struct_def = None;
+        self.scopes.clear();
+        self.even_words = false;
+        self.big_endian = false;
+        self.warn_page = false;
+        self.warn_jmp_bug = false;
+        self.warn_once = false;
+        self.dbg_output_only = false;
+        self.output_flag = true;
+        self.auto_branch = false;
+        self.long_branches = false;
+        self.aliases = false;
+        self.zp_opt = self.zp_opt_default;
+        self.cpu = self.cpu_default;
+        self.signed = false;
+
+        for line in self.imports.clone() {
+            if let Err(msg) = self.import_symbol(line) {
+                self.error(msg);
+            }
+        }
+
+        for path in self.define_files.clone() {
+            let src = match source::from_resolver(&path, self.resolver.as_ref()) {
+                Ok(src) => src,
+                Err(e) => {
+                    self.error(format!("Error reading define file '{}': {}", path, e));
+                    continue;
+                }
+            };
+            for line in src {
+                if let Err(msg) = self.define_symbol(line) {
+                    self.error(msg);
+                }
+            }
+        }
 
         while let Some(line) = self.src_stk.next() {
+            // Binary input would only give a flood of errors, so give up on it right away.
+            if let Err(msg) = line.check_text() {
+                self.error(msg);
+                break;
+            }
             if let Err(msg) = self.pass1_line(line) {
-                eprintln!("{}", msg);
-                self.errcount += 1;
+                self.error(msg);
+            }
+        }
+
+        for cond in mem::take(&mut self.if_stack) {
+            if let Err(msg) = cond.slice.err::<()>("unmatched .if statement") {
+                self.error(msg);
             }
         }
 
-        if !self.if_stack.is_empty() {
-            eprintln!("unmatched if statements");
-            self.errcount += 1;
+        if !self.pc_stack.is_empty() {
+            self.error("unmatched .pushpc statements".to_string());
+        }
+
+        if !self.org_stack.is_empty() {
+            self.error("unmatched .pushorg statements".to_string());
+        }
+
+        if self.page_start.is_some() {
+            self.error("unmatched .page statement".to_string());
+        }
+
+        if self.struct_def.is_some() {
+            self.error("unmatched .struct statement".to_string());
+        }
+
+        if self.enum_next.is_some() {
+            self.error("unmatched .enum statement".to_string());
+        }
+
+        if !self.scopes.is_empty() {
+            self.error("unmatched .scope statement".to_string());
+        }
+
+        if self.in_block_comment {
+            self.error("unterminated '/*' comment".to_string());
         }
 
         if self.errcount == 0 {
@@ -192,70 +682,415 @@ impl Assembler {
         }
     }
 
+    /// Predefine a symbol from a line of a symbol table dump (see `AsmInfo::dump_symtab`).
+    ///
+    /// Blank lines and undefined symbols are skipped.
+    fn import_symbol(&mut self, line: Rc<Line>) -> Result<(), String> {
+        let mut fields = line.text.trim_start().splitn(3, " : ");
+        let name = fields.next().unwrap_or_default().trim_end();
+        if name.is_empty() {
+            return Ok(());
+        }
+        if !name
+            .split("::")
+            .flat_map(|part| part.split('.'))
+            .all(|part| part.starts_with(is_alpha) && part.chars().all(is_alphanum))
+        {
+            return line.err(&format!("bad symbol name '{}'", name));
+        }
+        let Some(value) = fields.next() else {
+            return Ok(());
+        };
+        let Ok(value) = u16::from_str_radix(value.trim_end(), 16) else {
+            return line.err(&format!("bad value '{}' for symbol '{}'", value, name));
+        };
+        let start = line.text.chars().take_while(|c| c.is_whitespace()).count() as u16;
+        let end = start + name.chars().count() as u16;
+        let slice = Rc::new(LineSlice::new(line.clone(), start, end));
+        let comment = fields.next().map(String::from);
+
+        let sym = self.lookup(name, slice.clone());
+        sym.define(value, slice)?;
+        sym.comment = comment;
+        Ok(())
+    }
+
+    /// Predefine a symbol from a define file line, `{name}={expr}`. Blank lines and `#`
+    /// comment lines are skipped.
+    fn define_symbol(&mut self, line: Rc<Line>) -> Result<(), String> {
+        let text = line.text.trim();
+        if text.is_empty() || text.starts_with('#') {
+            return Ok(());
+        }
+        self.cur_line = Some(line.clone());
+        let parsed = self.parse_line(line.clone())?;
+        match (parsed.label, parsed.action) {
+            (Some(label), Some(action)) if action.is_equ() => {
+                action.pass1(self, Some(label))?;
+                Ok(())
+            }
+            _ => line.err("expected '{name}={expr}'"),
+        }
+    }
+
     /// Handle a single line in pass2.
-    fn pass2_line(
-        &mut self,
-        line_vec_index: usize,
-        line: &ParsedLine,
-        output: &mut Vec<u8>,
-    ) -> Result<(), String> {
+    fn pass2_line(&mut self, line_vec_index: usize, line: &ParsedLine) -> Result<(), String> {
         if let Some(action) = &line.action {
-            let listing_pc = action.eval_equ(self).unwrap_or(self.pc);
+            let mut listing_pc = action.eval_equ(self).unwrap_or(self.pc);
+            let mut store_pc = self.store_pc();
 
-            let new_bytes = action.pass2(self)?;
-            self.pc = self.pc.wrapping_add(new_bytes.len() as u16);
+            let pad = action.align_pad(self);
+            self.pc = self.pc.wrapping_add(pad);
+            let mut new_bytes = vec![0; pad as usize];
+            let pc = self.pc;
+            let action_bytes = action.pass2(self)?;
+            if self.pc != pc && !action_bytes.is_empty() {
+                // The action moved the PC before its bytes, as `.vectors` does.
+                listing_pc = self.pc;
+                store_pc = self.store_pc();
+            }
+            if action.is_opcode() && action_bytes.len() != line.size as usize {
+                // Everything after would be at a different address than pass1 found.
+                return action.line_slice().err(&format!(
+                    "phase error: {} bytes in pass1, {} in pass2",
+                    line.size,
+                    action_bytes.len()
+                ));
+            }
+            let disasm_column = self.disasm_column(
+                action
+                    .is_opcode()
+                    .then(|| disassemble(&action_bytes, self.pc))
+                    .flatten(),
+            );
+            if self.warn_page && action.is_opcode() {
+                let start = self.pc;
+                let end = start.wrapping_add((action_bytes.len() - 1) as u16);
+                if (start >> 8) != (end >> 8) {
+                    self.warn(
+                        &action.line_slice(),
+                        &format!("instruction at {:04X} crosses a page boundary", start),
+                    );
+                }
+            }
+            if action.is_opcode() {
+                if let Some(cycles) = cycles(&action_bytes) {
+                    self.cycle_log.push((self.pc, cycles));
+                }
+            }
+            self.pc = self.pc.wrapping_add(action_bytes.len() as u16);
+            new_bytes.extend(action_bytes);
             if let Some(listing) = self.listing.as_mut() {
                 let i = self.listing_index.as_ref().unwrap()[line_vec_index];
                 let mut l = String::new();
                 l.push_str(&format!("{:06} {:04X} ", line.line.line_num, listing_pc));
-                for i in 0..BYTES_PER_LINE {
+                for i in 0..self.list_bytes {
                     if let Some(b) = new_bytes.get(i) {
                         l.push_str(&format!("{:02X}", *b));
                     } else {
                         l.push_str("  ");
                     }
                 }
-                l.push_str(&format!(" {}\n", line.line.text));
+                l.push_str(&format!(" {}{}\n", disasm_column, line.line.text));
+                // Any remaining bytes go on continuation lines.
+                for (chunk_i, chunk) in new_bytes.chunks(self.list_bytes).enumerate().skip(1) {
+                    let chunk_pc = listing_pc.wrapping_add((chunk_i * self.list_bytes) as u16);
+                    l.push_str(&format!("       {:04X} ", chunk_pc));
+                    for b in chunk {
+                        l.push_str(&format!("{:02X}", *b));
+                    }
+                    l.push('\n');
+                }
                 listing[i] = l;
             }
-            if self.output_flag {
-                output.extend(new_bytes);
+            if self.output_flag && self.struct_def.is_none() {
+                self.add_segment_bytes(store_pc, &new_bytes);
             }
         }
         Ok(())
     }
 
+    /// Add a heading to the listing, above the line being listed: the title, and the
+    /// subtitle after a ` - `. A page break (form feed) goes first, if `page_break`.
+    pub fn list_heading(&mut self, page_break: bool) {
+        let Some(listing) = self.listing.as_mut() else {
+            return;
+        };
+        let mut heading = if page_break { "\x0C" } else { "" }.to_string();
+        let parts = [&self.list_title, &self.list_subtitle];
+        heading.push_str(&Vec::from_iter(parts.into_iter().flatten().cloned()).join(" - "));
+        listing.insert(listing.len() - 1, heading);
+    }
+
+    /// Return the listing's disassembly column for a line, padded to its width; empty if
+    /// the listing has no such column.
+    fn disasm_column(&self, disasm: Option<String>) -> String {
+        if self.list_disasm {
+            format!(
+                "{:<width$} ",
+                disasm.unwrap_or_default(),
+                width = DISASM_WIDTH - 1
+            )
+        } else {
+            String::new()
+        }
+    }
+
+    /// Return the address the next byte will be loaded at, ignoring any `.pushpc`.
+    fn store_pc(&self) -> u16 {
+        self.pc_stack
+            .iter()
+            .rev()
+            .fold(self.pc, |pc, (saved, logical)| {
+                saved.wrapping_add(pc.wrapping_sub(*logical))
+            })
+    }
+
+    /// Add output bytes loaded at the given address to the active `.segment`.
+    fn add_segment_bytes(&mut self, addr: u16, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        let regions = &mut self.sections[self.cur_section].regions;
+        if let Some((start, seg)) = regions.last_mut() {
+            if start.wrapping_add(seg.len() as u16) == addr {
+                seg.extend(bytes);
+                return;
+            }
+        }
+        regions.push((addr, bytes.to_vec()));
+    }
+
+    /// Return the bytes output so far that load at an address in `start..end`, in output
+    /// order.
+    pub fn output_bytes(&self, start: u32, end: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (addr, region) in self.sections.iter().flat_map(|s| &s.regions) {
+            for (i, b) in region.iter().enumerate() {
+                if (start..end).contains(&(*addr as u32 + i as u32)) {
+                    bytes.push(*b);
+                }
+            }
+        }
+        bytes
+    }
+
+    /// Make the named `.segment` active, creating it if it's new.
+    pub fn switch_section(&mut self, name: &str) {
+        self.sections[self.cur_section].pc = self.pc;
+        self.cur_section = match self.sections.iter().position(|s| s.name == name) {
+            Some(i) => i,
+            None => {
+                self.sections.push(Section::new(name.to_string()));
+                self.sections.len() - 1
+            }
+        };
+        self.pc = self.sections[self.cur_section].pc;
+    }
+
+    /// Save the active `.segment` and the PC, for `.pushorg`.
+    pub fn push_org(&mut self) {
+        self.org_stack.push((self.cur_section, self.pc));
+    }
+
+    /// Go back to the `.segment` and PC saved by the last `.pushorg`, returning false if there
+    /// was none.
+    pub fn pop_org(&mut self) -> bool {
+        let Some((section, pc)) = self.org_stack.pop() else {
+            return false;
+        };
+        self.sections[self.cur_section].pc = self.pc;
+        self.cur_section = section;
+        self.pc = pc;
+        true
+    }
+
+    /// Start a pass with only the default `.segment`.
+    fn reset_sections(&mut self) {
+        self.sections = vec![Section::new(String::new())];
+        self.cur_section = 0;
+    }
+
+    /// Join the `.segment`s in order, returning the output bytes and setting `segments`.
+    fn link(&mut self) -> Vec<u8> {
+        let mut output: Vec<u8> = Vec::with_capacity((u16::MAX as usize) + 1);
+        self.segments.clear();
+        for (addr, bytes) in mem::take(&mut self.sections)
+            .into_iter()
+            .flat_map(|s| s.regions)
+        {
+            output.extend(&bytes);
+            if let Some((start, seg)) = self.segments.last_mut() {
+                if start.wrapping_add(seg.len() as u16) == addr {
+                    seg.extend(bytes);
+                    continue;
+                }
+            }
+            self.segments.push((addr, bytes));
+        }
+        output
+    }
+
     /// Final assembly.
     pub fn pass2(&mut self) -> Result<Vec<u8>, String> {
         assert!(self.errcount == 0);
         self.pc = DEFAULT_PC;
         self.pass = Pass::Pass2;
-        let mut output: Vec<u8> = Vec::with_capacity((u16::MAX as usize) + 1);
+        self.pc_stack.clear();
+        self.org_stack.clear();
+        self.reset_sections();
+        self.page_start = None;
+        self.struct_def = None;
+        self.scopes.clear();
+        self.even_words = false;
+        self.big_endian = false;
+        self.warn_page = false;
+        self.warn_jmp_bug = false;
+        self.warn_once = false;
+        self.dbg_output_only = false;
+        self.output_flag = true;
+        self.zp_opt = self.zp_opt_default;
+        self.cpu = self.cpu_default;
         let lines = mem::take(&mut self.parsed_lines);
 
         for (i, parsed_line) in lines.iter().enumerate() {
-            if let Err(msg) = self.pass2_line(i, parsed_line, &mut output) {
-                eprintln!("{}", msg);
-                self.errcount += 1;
+            self.line_index = i;
+            if let Err(msg) = self.pass2_line(i, parsed_line) {
+                self.error(msg);
             }
         }
+        self.count_repeated_warnings();
+        self.report_cycles();
 
         if self.errcount == 0 {
-            Ok(output)
+            let bytes = self.link();
+            self.pad_rom(bytes)
         } else {
             Err(format!("{} errors in pass 2", self.errcount))
         }
     }
 
-    /// Output a debug info string.
+    /// Lay the linked output out as a ROM image, if there is a ROM size: the image starts at
+    /// the first segment's address, each segment is placed by its address, and the gaps and
+    /// the rest of the image are the fill byte. A segment outside the image is an error.
+    fn pad_rom(&mut self, bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+        let Some(size) = self.rom_size else {
+            return Ok(bytes);
+        };
+        let base = self
+            .segments
+            .first()
+            .map_or(DEFAULT_PC, |(start, _)| *start);
+        let mut image = vec![self.rom_fill; size];
+        for (start, seg) in &self.segments {
+            let offset = (*start as usize).checked_sub(base as usize);
+            match offset.filter(|offset| offset + seg.len() <= size) {
+                Some(offset) => image[offset..offset + seg.len()].copy_from_slice(seg),
+                None => {
+                    return Err(format!(
+                        "output at {:04X}-{:04X} is outside the {}-byte ROM starting at {:04X}",
+                        start,
+                        *start as usize + seg.len() - 1,
+                        size,
+                        base
+                    ))
+                }
+            }
+        }
+        self.segments = vec![(base, image.clone())];
+        Ok(image)
+    }
+
+    /// Issue a warning at a given slice: it's printed, and saved in the warnings list.
+    ///
+    /// Under `.warnonce`, a warning already issued from the same source line is only
+    /// counted, as when a macro is expanded many times.
+    pub fn warn(&mut self, slice: &LineSlice, msg: &str) {
+        let key = if self.warn_once {
+            let line = slice.line();
+            let from = line.origin.as_ref().unwrap_or(line);
+            let key = (from.pos(), msg.to_string());
+            if let Some((_, _, count)) = self.warn_repeats.get_mut(&key) {
+                *count += 1;
+                return;
+            }
+            Some(key)
+        } else {
+            None
+        };
+        let msg = format!("{}: warning: {}", slice.pos(), msg);
+        eprintln!("{}", msg);
+        if let Some(key) = key {
+            let indices = (self.warnings.len(), self.diagnostics.len(), 1);
+            self.warn_repeats.insert(key, indices);
+        }
+        self.warnings.push(msg.clone());
+        self.diagnostics.push(AsmError {
+            message: msg,
+            warning: true,
+        });
+    }
+
+    /// Note how many times each repeated warning was issued, in its saved message, and print
+    /// a summary of them.
+    fn count_repeated_warnings(&mut self) {
+        let mut repeats: Vec<_> = self
+            .warn_repeats
+            .drain()
+            .filter_map(|(_, (w, d, count))| (count > 1).then_some((w, d, count)))
+            .collect();
+        repeats.sort();
+        for (w, d, count) in repeats {
+            let msg = format!("{} (repeated {} times)", self.warnings[w], count);
+            eprintln!("{}", msg);
+            self.warnings[w] = msg.clone();
+            self.diagnostics[d].message = msg;
+        }
+    }
+
+    /// Print the total base cycle count of the instructions in each `.cycles` range, and
+    /// save it in the cycle reports.
+    fn report_cycles(&mut self) {
+        for (start, end, slice) in mem::take(&mut self.cycle_spans) {
+            let total: u32 = self
+                .cycle_log
+                .iter()
+                .filter(|(pc, _)| (start..end).contains(pc))
+                .map(|(_, cycles)| cycles)
+                .sum();
+            let msg = format!(
+                "{}: {:04X}-{:04X} takes {} cycles",
+                slice.pos(),
+                start,
+                end,
+                total
+            );
+            eprintln!("{}", msg);
+            self.cycle_reports.push(msg);
+        }
+    }
+
+    /// Issue an error: it's printed, counted, and saved in the diagnostics.
+    fn error(&mut self, msg: String) {
+        eprintln!("{}", msg);
+        self.errcount += 1;
+        self.diagnostics.push(AsmError {
+            message: msg,
+            warning: false,
+        });
+    }
+
+    /// Output a line to each debug stream, for a label on a statement of `size` bytes.
     fn debug_label(
         &mut self,
         label: &str,
         slice: Rc<LineSlice>,
         value: u16,
+        size: u16,
         comment: Option<&str>,
     ) -> Result<(), String> {
-        if let Some(f) = &self.debug_fmt {
+        for (name, f) in &self.debug_fmt {
+            let out = self.debug_str.entry(name.clone()).or_default();
             let mut chars = f.chars();
             while let Some(c) = chars.next() {
                 if c == '{' {
@@ -264,11 +1099,11 @@ impl Assembler {
                             if chars.next() != Some('}') {
                                 return slice.err("bad debug format string");
                             }
-                            self.debug_str
-                                .push_str(&comment.unwrap_or("").trim_end().replace("\n", " "));
+                            out.push_str(&comment.unwrap_or("").trim_end().replace("\n", " "));
                         }
                         Some('V') => {
                             let mut starting_offset: u32 = 0;
+                            let mut width: usize = 0;
                             let mut wrapped_c = chars.next();
                             let neg_flag = {
                                 if wrapped_c == Some('-') {
@@ -281,6 +1116,16 @@ impl Assembler {
                             while let Some(c) = wrapped_c {
                                 if c == '}' {
                                     break;
+                                } else if c == ':' {
+                                    // A decimal width to zero-pad to follows.
+                                    wrapped_c = chars.next();
+                                    while let Some(digit) = wrapped_c.and_then(|c| c.to_digit(10)) {
+                                        width = width * 10 + digit as usize;
+                                        wrapped_c = chars.next();
+                                    }
+                                    if wrapped_c != Some('}') {
+                                        return slice.err("bad debug format string");
+                                    }
                                 } else if let Some(digit) = c.to_digit(16) {
                                     wrapped_c = chars.next();
                                     starting_offset = starting_offset * 16 + digit;
@@ -291,8 +1136,8 @@ impl Assembler {
                             if neg_flag {
                                 starting_offset = starting_offset.wrapping_neg();
                             }
-                            self.debug_str.push_str(&format!(
-                                "{:X}",
+                            out.push_str(&format!(
+                                "{:0width$X}",
                                 starting_offset.wrapping_add(value as u32)
                             ));
                         }
@@ -300,26 +1145,30 @@ impl Assembler {
                             if chars.next() != Some('}') {
                                 return slice.err("bad debug format string");
                             }
-                            self.debug_str.push_str(label);
+                            out.push_str(label);
+                        }
+                        Some('S') => {
+                            if chars.next() != Some('}') {
+                                return slice.err("bad debug format string");
+                            }
+                            out.push_str(&format!("{:X}", size));
                         }
                         _ => {
                             return slice.err("bad dbg format string");
                         }
                     }
                 } else {
-                    self.debug_str.push(c);
+                    out.push(c);
                 }
             }
-            self.debug_str.push('\n');
-            Ok(())
-        } else {
-            panic!()
+            out.push('\n');
         }
+        Ok(())
     }
 
     /// Define a new label at the current PC, complaining if it was redefined.
     ///
-    /// In the first pass, this also outputs the debug string.
+    /// In the first pass, this also outputs the debug string, once the line's size is known.
     pub fn def_label(
         &mut self,
         label: &str,
@@ -327,17 +1176,113 @@ impl Assembler {
         comment_label: Option<String>,
     ) -> Result<(), String> {
         let pc = self.pc;
-        if self.pass == Pass::Pass1 && self.debug_fmt.is_some() {
-            self.debug_label(label, slice.clone(), pc, comment_label.as_deref())?
+        let name = self.scoped_name(label);
+        if self.pass == Pass::Pass1
+            && !self.debug_fmt.is_empty()
+            && (self.output_flag || !self.dbg_output_only)
+        {
+            self.debug_pending = Some((name.clone(), slice.clone(), pc, comment_label.clone()));
         }
         self.def_symbol(label, slice, pc)?;
+        let sym = self.symtab.get_mut(&name).unwrap();
+        sym.label = true;
         if let Some(comment) = comment_label {
-            let sym = self.symtab.get_mut(label).unwrap();
             sym.comment = Some(comment);
         }
         Ok(())
     }
 
+    /// Remove a reference to a name that turned out not to be a symbol (such as a keyword
+    /// argument), deleting the symbol if nothing else refers to it.
+    pub fn forget_ref(&mut self, slice: &Rc<LineSlice>) {
+        let name = self
+            .resolve(slice.text())
+            .unwrap_or_else(|| slice.text().to_string());
+        if let Some(sym) = self.symtab.get_mut(&name) {
+            sym.references.remove(slice);
+            if sym.value.is_none() && sym.references.is_empty() {
+                self.symtab.remove(&name);
+            }
+        }
+    }
+
+    /// Mark the symbol a name refers to as exported, by `.global`.
+    pub fn export(&mut self, slice: &Rc<LineSlice>) -> Result<(), String> {
+        let name = self
+            .resolve(slice.text())
+            .unwrap_or_else(|| slice.text().to_string());
+        let sym = self.lookup(&name, slice.clone());
+        if sym.value.is_none() {
+            return slice.err(&format!("'{}' undefined", slice.text()));
+        }
+        sym.exported = true;
+        Ok(())
+    }
+
+    /// Return the name qualified by the open `.scope`s, as in `outer.inner.name`.
+    pub fn scoped_name(&self, name: &str) -> String {
+        if self.scopes.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", self.scopes.join("."), name)
+        }
+    }
+
+    /// Find the defined symbol a name refers to, searching from the innermost `.scope` out
+    /// to the top level.
+    ///
+    /// In pass2, the symbols defined by this point in pass1 are searched first, so a name
+    /// resolves to the symbol it did in pass1 even if a closer one is defined after it; only
+    /// a forward reference is resolved against every symbol.
+    pub fn resolve(&self, name: &str) -> Option<String> {
+        if self.pass == Pass::Pass2 {
+            let here = 2 * self.line_index + 1;
+            if let Some(found) = self.resolve_where(name, |sym| sym.visible_from < here) {
+                return Some(found);
+            }
+        }
+        self.resolve_where(name, |_| true)
+    }
+
+    /// Find the innermost defined symbol a name could refer to that passes `filter`.
+    fn resolve_where(&self, name: &str, filter: impl Fn(&Symbol) -> bool) -> Option<String> {
+        (0..=self.scopes.len()).rev().find_map(|depth| {
+            let qualified = if depth == 0 {
+                name.to_string()
+            } else {
+                format!("{}.{}", self.scopes[..depth].join("."), name)
+            };
+            match self.symtab.get(&qualified) {
+                Some(sym) if sym.value.is_some() && filter(sym) => Some(qualified),
+                _ => None,
+            }
+        })
+    }
+
+    /// Return where a symbol being defined in pass1 is, for `Symbol::visible_from`: twice the
+    /// index of the line, and one more if the line's expressions were evaluated before the
+    /// definition (as for `=`), so they didn't see it. Pass2 sees a symbol from a line if it's
+    /// less than twice that line's index plus one. Lines in an `.enum` don't go on to pass2, so
+    /// their symbols are seen from the next line that does.
+    fn def_position(&self, after_eval: bool) -> usize {
+        2 * self.parsed_lines.len() + (after_eval && self.enum_next.is_none()) as usize
+    }
+
+    /// Note a reference to a name in an expression, returning the value of the symbol it
+    /// refers to, if it's defined.
+    ///
+    /// Inside a `.scope`, a name that isn't defined yet could still be defined later in
+    /// any enclosing scope, so in the first pass it isn't added to the symbol table; the
+    /// second pass will find it.
+    pub fn lookup_ref(&mut self, name: &str, ref_slice: Rc<LineSlice>) -> Option<u32> {
+        let name = match self.resolve(name) {
+            Some(name) => name,
+            None if self.pass == Pass::Pass1 && !self.scopes.is_empty() => return None,
+            None => name.to_string(),
+        };
+        self.lookup(&name, ref_slice).expr_value()
+    }
+
     /// Look-up the symbol in the symbol table, creating it as undefined if it didn't exist.
     pub fn lookup(&mut self, name: &str, ref_slice: Rc<LineSlice>) -> &mut Box<Symbol> {
         if !self.symtab.contains_key(name) {
@@ -351,6 +1296,47 @@ impl Assembler {
         sym
     }
 
+    /// Set a symbol by `.set`, in either pass, so each line sees the value last set before it.
+    pub fn set_symbol(
+        &mut self,
+        name: &str,
+        slice: Rc<LineSlice>,
+        value: u16,
+        negative: bool,
+    ) -> Result<(), String> {
+        let name = &self.scoped_name(name);
+        let position = self.def_position(true);
+        let pass1 = self.pass == Pass::Pass1;
+        let sym = self.lookup(name, slice.clone());
+        if pass1 && sym.value.is_none() {
+            sym.visible_from = position;
+        }
+        sym.set(value, slice)?;
+        sym.negative = negative;
+        Ok(())
+    }
+
+    /// Define a symbol by `=`, as `def_symbol` does, noting whether its value was negative so
+    /// an expression sees it as negative rather than as its word.
+    pub fn def_equ(
+        &mut self,
+        name: &str,
+        slice: Rc<LineSlice>,
+        value: u16,
+        negative: bool,
+    ) -> Result<(), String> {
+        self.def_symbol(name, slice, value)?;
+        let position = self.def_position(true);
+        let pass1 = self.pass == Pass::Pass1;
+        let sym = self.symtab.get_mut(&self.scoped_name(name)).unwrap();
+        if pass1 {
+            // Its own expression didn't see it.
+            sym.visible_from = position;
+        }
+        sym.negative = negative;
+        Ok(())
+    }
+
     /// Define a new symbol, complaining if it was redefined.
     pub fn def_symbol(
         &mut self,
@@ -358,11 +1344,15 @@ impl Assembler {
         slice: Rc<LineSlice>,
         value: u16,
     ) -> Result<(), String> {
+        let name = &self.scoped_name(name);
         match self.pass {
             Pass::None => panic!("symbol def outside of pass"),
             Pass::Pass1 => {
+                let position = self.def_position(false);
                 let sym = self.lookup(name, slice.clone());
-                sym.define(value, slice)
+                sym.define(value, slice)?;
+                sym.visible_from = position;
+                Ok(())
             }
             Pass::Pass2 => {
                 if let Some(definition) = self.lookup(name, slice.clone()).value {
@@ -389,9 +1379,12 @@ impl Assembler {
 mod tests {
     use std::rc::Rc;
 
+    use better_peekable::BetterPeekable;
+
     use crate::{
-        assemble,
-        source::{self, from_str, LineSlice},
+        assemble, assemble_str, assemble_with_diagnostics,
+        parse::LineChars,
+        source::{self, from_str, Line, LineSlice},
     };
 
     use super::{Assembler, Pass};
@@ -421,7 +1414,24 @@ mod tests {
         assert_eq!(asm.lookup("foo", foo).value, Some(0));
         assert_eq!(asm.lookup("bar", bar).value, Some(2));
         assert_eq!(asm.lookup("foobar", foobar).value, Some(4));
-        assert_eq!(asm.pc, 6);
+        assert_eq!(asm.pc(), 6);
+    }
+
+    #[test]
+    fn test_pc() {
+        let src = "        .org $1234
+        nop";
+        let mut asm = Box::new(Assembler::new(from_str(src, "pc.s"), false));
+        asm.pass1().unwrap();
+        assert_eq!(asm.pc(), 0x1235);
+        let line = Rc::new(Line::new("*", "pc.s", 1));
+        let star = |asm: &mut Assembler| {
+            let e = asm.parse_expr(&mut LineChars::new(&line).better_peekable());
+            e.unwrap().eval(asm)
+        };
+        assert_eq!(star(&mut asm), Ok(asm.pc() as u32));
+        asm.set_pc(0xC000);
+        assert_eq!(star(&mut asm), Ok(0xC000));
     }
 
     #[test]
@@ -437,6 +1447,27 @@ bar     .equ foo*2
         assert_eq!(result.symtab["bar"].value, Some(2468));
     }
 
+    #[test]
+    fn test_dbg_output_only() {
+        let src = "
+        .dbg '{L}:{V}'
+        .off
+ram     .ds 2
+        .on
+code    nop
+        .off
+        .dbgoutput on
+ram2    .ds 2
+        .on
+code2   nop
+        .dbgoutput off
+        .off
+ram3    .ds 1";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(info.debug_str, "ram:0\ncode:2\ncode2:5\nram3:6\n");
+        assert_eq!(info.bytes, vec![0xEA, 0xEA]);
+    }
+
     #[test]
     fn test_neg_dbg() {
         let src = "
@@ -447,6 +1478,144 @@ foo     .word foo";
         assert_eq!(info.debug_str.as_str(), "foo:234\n");
     }
 
+    #[test]
+    fn test_dbg_width() {
+        let src = "
+        .org $8000
+        .dbg '{L}:{V:4}:{V-7FC0:04}:{V}:{V:2}'
+foo     nop";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(info.debug_str.as_str(), "foo:8000:0040:8000:8000\n");
+        let src = "
+        .dbg '{V:4}'
+        .org $42
+        nop
+foo     nop";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(info.debug_str.as_str(), "0043\n");
+        assert!(assemble(source::from_str(".dbg '{V:4x}'\nfoo nop", "src"), false).is_err());
+    }
+
+    #[test]
+    fn test_dbg_size() {
+        let src = "
+        .dbg '{L}:{V}:{S}'
+foo     .byte 1,2,3
+bar
+baz     .ds 20
+        .mac two
+        .byte 1,2
+        .endm
+qux     two";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(
+            info.debug_str.as_str(),
+            "foo:0:3\nbar:3:0\nbaz:3:14\nqux:17:0\n"
+        );
+        assert!(assemble(source::from_str(".dbg '{S'\nfoo nop", "src"), false).is_err());
+    }
+
+    #[test]
+    fn test_utf8() {
+        let src = "; café
+name    lda #1  ; naïve
+        .byte \"ü\", 2 /* é */ + 1 // résumé";
+        let info = assemble(from_str(src, "utf8.s"), true).unwrap();
+        assert_eq!(info.bytes, vec![0xA9, 1, 0xC3, 0xBC, 3]);
+        assert_eq!(info.symtab["name"].comment.as_deref(), Some("café\n"));
+        assert!(info
+            .listing
+            .unwrap()
+            .contains(".byte \"ü\", 2 /* é */ + 1 // résumé"));
+
+        // Positions count characters, not bytes.
+        let info =
+            crate::assemble_with_diagnostics(from_str("  .byte \"é\", nope ; ü", "utf8.s"), false);
+        assert_eq!(
+            info.diagnostics[0].message,
+            "utf8.s:1:14: 'nope' undefined\n  .byte \"é\", nope ; ü\n             ^^^^"
+        );
+    }
+
+    #[test]
+    fn test_unmatched_if() {
+        let info =
+            crate::assemble_with_diagnostics(from_str("  nop\n  .if 1\n  nop", "if.s"), false);
+        assert_eq!(info.diagnostics.len(), 1);
+        assert_eq!(
+            info.diagnostics[0].message,
+            "if.s:2:3: unmatched .if statement\n  .if 1\n  ^^^^^"
+        );
+
+        // Each unclosed `.if` gets its own error.
+        let src = "  .if 1\n  .if 0\n  .else\n  .if 1\n  .endif";
+        let info = crate::assemble_with_diagnostics(from_str(src, "if.s"), false);
+        let messages: Vec<_> = info.diagnostics.iter().map(|d| &d.message[..8]).collect();
+        assert_eq!(messages, vec!["if.s:1:3", "if.s:2:3"]);
+    }
+
+    #[test]
+    fn test_binary_source() {
+        let src = "  nop\n\x7FELF\x02\x01\0\0\n  lda #\0\n  foo bar";
+        let info = crate::assemble_with_diagnostics(from_str(src, "a.out"), false);
+        assert_eq!(info.diagnostics.len(), 1);
+        assert_eq!(
+            info.diagnostics[0].message,
+            "a.out:2: input does not look like text source (control character U+007F)"
+        );
+
+        let long = format!("  .byte {}", "1,".repeat(40000));
+        let info = crate::assemble_with_diagnostics(from_str(&long, "long.s"), false);
+        assert!(info.diagnostics[0].message.contains("line longer than"));
+
+        // A line's length is checked in bytes, which its indices are kept in.
+        let long = format!("  .byte 1 ; {}", "\u{e9}".repeat(40000));
+        let info = crate::assemble_with_diagnostics(from_str(&long, "long.s"), false);
+        assert!(info.diagnostics[0]
+            .message
+            .contains("line longer than 65535 bytes"));
+
+        // Tabs, form feeds and carriage returns are fine, as is a Ctrl-Z ending the file.
+        let src = "\tnop\x0C\r\n";
+        assert_eq!(crate::assemble_str(src, "ok.s"), Ok(vec![0xEA]));
+        assert_eq!(crate::assemble_str("\tnop\r\n\x1A", "ok.s"), Ok(vec![0xEA]));
+        assert_eq!(crate::assemble_str("\tnop\x1A\x1A", "ok.s"), Ok(vec![0xEA]));
+        assert!(crate::assemble_str("\tnop\x1A\n\tnop", "ok.s").is_err());
+    }
+
+    #[test]
+    fn test_slash_comments() {
+        let src = "
+// prints a character
+chrout  nop
+        lda #8/2    // divide, then comment
+        .byte 6 / 3, '//', \"/*\" // all data
+        jsr chrout//no space";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(
+            info.bytes,
+            vec![0xEA, 0xA9, 4, 2, b'/', b'/', b'/', b'*', 0x20, 0x00, 0x00]
+        );
+        assert_eq!(
+            info.symtab["chrout"].comment.as_deref(),
+            Some("prints a character\n")
+        );
+
+        let src = "
+        lda #1  /* a comment */ ; and another
+/*
+        lda #2
+        .byte 'unterminated
+*/      lda #3
+        lda /* inline */ #4 /* spanning
+        lines */
+        /**/ nop";
+        let info = assemble(source::from_str(src, "src"), true).unwrap();
+        assert_eq!(info.bytes, vec![0xA9, 1, 0xA9, 3, 0xA9, 4, 0xEA]);
+        assert!(info.listing.unwrap().contains("A903   */      lda #3\n"));
+        assert!(assemble(source::from_str("nop /* open", "src"), false).is_err());
+    }
+
     #[test]
     fn test_reset_building_comment() {
         let src = "
@@ -464,6 +1633,35 @@ bar     .word foo";
         );
     }
 
+    #[test]
+    fn test_doc_paras() {
+        let src = "
+        ;first
+        ;
+        ;second
+        ;
+foo     nop
+        .docparas on
+        ;
+        ;one
+        ;still one
+        ;
+        ;two
+        ;
+bar     nop
+        ;reset
+        ;
+
+baz     nop";
+        let info = assemble(from_str(src, "src"), false).unwrap();
+        assert_eq!(info.symtab["foo"].comment, None);
+        assert_eq!(
+            info.symtab["bar"].comment.as_deref(),
+            Some("one\nstill one\n\ntwo\n")
+        );
+        assert_eq!(info.symtab["baz"].comment, None);
+    }
+
     #[test]
     fn test_relop() {
         let src = "
@@ -489,6 +1687,83 @@ NE2 = FOO <> BAR
         assert_eq!(info.symtab["NE2"].value, Some(1));
     }
 
+    #[test]
+    fn test_relop_c_spellings() {
+        let src = "
+FOO = 2
+BAR = 3
+E = FOO = BAR
+E2 = FOO == BAR
+E3 = FOO == FOO
+NE = FOO <> BAR
+NE2 = FOO != BAR
+NE3 = FOO != FOO
+S = \"ab\" != \"ab\"
+        ";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(info.symtab["E"].value, info.symtab["E2"].value);
+        assert_eq!(info.symtab["E3"].value, Some(1));
+        assert_eq!(info.symtab["NE"].value, info.symtab["NE2"].value);
+        assert_eq!(info.symtab["NE3"].value, Some(0));
+        assert_eq!(info.symtab["S"].value, Some(0));
+        assert!(assemble(source::from_str("X = 1 ! 2", "src"), false).is_err());
+    }
+
+    #[test]
+    fn test_scope() {
+        let src = "
+foo = $10
+        .scope sound
+foo     = $20
+play    lda #foo
+        jsr later
+        jmp outer
+        .scope voice
+foo     .byte foo, bar
+        .endscope
+bar     = 3
+later   rts
+        .endscope
+outer   .byte foo, sound.foo, sound.voice.foo & $FF, sound.bar
+        ";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(info.symtab["foo"].value, Some(0x10));
+        assert_eq!(info.symtab["sound.foo"].value, Some(0x20));
+        assert_eq!(info.symtab["sound.play"].value, Some(0));
+        assert_eq!(info.symtab["sound.voice.foo"].value, Some(8));
+        assert_eq!(info.symtab["sound.later"].value, Some(10));
+        assert!(!info.symtab.contains_key("play"));
+        assert!(!info.symtab.contains_key("sound"));
+        assert!(!info.symtab.contains_key("sound.outer"));
+        assert_eq!(
+            info.bytes,
+            vec![
+                0xA9, 0x20, 0x20, 0x0A, 0x00, 0x4C, 0x0B, 0x00, 0x08, 0x03, 0x60, 0x10, 0x20, 0x08,
+                0x03
+            ]
+        );
+        // Both passes resolve a name to the symbol defined by then, even if a closer one is
+        // defined after it.
+        let src = "foo = $10\n.scope s\n.byte foo\nfoo = $20\n.byte foo\n.endscope";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(info.bytes, vec![0x10, 0x20]);
+        let src = "foo = $10\n.scope s\nlda foo\nfoo = $1234\nlda foo\n.endscope";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(info.bytes, vec![0xA5, 0x10, 0xAD, 0x34, 0x12]);
+        // A forward reference finds the closest symbol.
+        let src = ".scope s\nlda foo\nfoo = $1234\n.endscope\nfoo = $10";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(info.bytes, vec![0xAD, 0x34, 0x12]);
+
+        assert!(assemble(source::from_str(".scope a", "src"), false).is_err());
+        assert!(assemble(source::from_str(".endscope", "src"), false).is_err());
+        assert!(assemble(
+            source::from_str(".scope a\nb rts\n.endscope\njmp b", "src"),
+            false
+        )
+        .is_err());
+    }
+
     #[test]
     fn test_listing() {
         let s = "; listing test
@@ -504,7 +1779,57 @@ BAR     .WORD FOO";
 000002 0000        .ORG $1234
 000003 1234
 000004 1234 3612   FOO     .WORD BAR
-000005 1236 3412   BAR     .WORD FOO\n"
+000005 1236 3412   BAR     .WORD FOO
+
+SYMBOLS
+FOO : 1234
+BAR : 1236
+"
+        );
+    }
+
+    #[test]
+    fn test_listing_wrap() {
+        let s = "        .byte 1,2,3,4,5
+        .word 1,2,3
+        .byte 6,7,8";
+        let info = assemble(from_str(s, "{s}"), true).unwrap();
+        assert_eq!(
+            &info.listing.unwrap(),
+            "LINENO PC   BYTES  LINE
+000001 0000 010203         .byte 1,2,3,4,5
+       0003 0405
+000002 0005 010002         .word 1,2,3
+       0008 000300
+000003 000B 060708         .byte 6,7,8
+
+SYMBOLS
+"
+        );
+    }
+
+    #[test]
+    fn test_listing_paths() {
+        let src = "        .org $1234
+foo     .word bar
+        .inc \"inc_2.s\"
+        .word foo";
+        let info = assemble(from_str(src, "main.s"), true).unwrap();
+        assert_eq!(
+            &info.listing.unwrap(),
+            "LINENO PC   BYTES  LINE
+000001 0000                .org $1234
+000002 1234 3612   foo     .word bar
+000003 1236                .inc \"inc_2.s\"
+====== inc_2.s
+000001 1236        ; this is a test of the include system
+000002 1236 3412   bar       .word foo
+====== main.s
+000004 1238 3412           .word foo
+
+SYMBOLS
+foo : 1234
+bar : 1236 : this is a test of the include system \n"
         );
     }
 
@@ -521,10 +1846,65 @@ BAR     = $5678";
 000001 0000
 000002 0000                .org $1234
 000003 1234 3412   FOO     .word FOO
-000004 5678        BAR     = $5678\n"
+000004 5678        BAR     = $5678
+
+SYMBOLS
+FOO : 1234
+BAR : 5678
+"
         )
     }
 
+    #[test]
+    fn test_warnonce() {
+        let src = "
+        .mac reloc
+        .pushpc $80FE
+        jmp *
+        .checkpage $80FE
+        .poppc
+        .endm
+        reloc
+        reloc
+        .warnonce on
+        reloc
+        reloc
+        reloc
+        .warnonce off
+        reloc
+        reloc";
+        let info = assemble(from_str(src, "src"), false).unwrap();
+        assert_eq!(info.warnings.len(), 5);
+        assert!(info.warnings[0].starts_with("src:8:"));
+        assert!(info.warnings[1].starts_with("src:9:"));
+        assert_eq!(
+            info.warnings[2],
+            "src:11:9: warning: 80FE-8100 crosses a page boundary (repeated 3 times)"
+        );
+        assert!(info.warnings[3].starts_with("src:15:"));
+        assert!(info.warnings[4].starts_with("src:16:"));
+        assert_eq!(info.diagnostics[2].message, info.warnings[2]);
+    }
+
+    #[test]
+    fn test_warnpage() {
+        let src = "
+        .org $80FE
+        lda $1234
+        .org $80FE
+        .warnpage on
+        lda $1234
+        lda $1234
+        .byte 1,2,3
+        .org $80FE
+        .warnpage off
+        lda $1234";
+        let info = assemble(from_str(src, "src"), false).unwrap();
+        assert_eq!(info.warnings.len(), 1);
+        assert!(info.warnings[0].starts_with("src:6:"));
+        assert!(info.warnings[0].contains("80FE"));
+    }
+
     #[test]
     fn test_underscore() {
         use crate::assemble_str;
@@ -534,4 +1914,20 @@ BAR     = $5678";
         let bytes = assemble_str(src, "src").unwrap();
         assert_eq!(bytes, vec![0x04, 0x00, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00]);
     }
+
+    #[test]
+    fn test_phase_error() {
+        let src = "\t.org $1000\n\tlda fwd\n\trts\nfwd = $10\n";
+        let info = assemble_with_diagnostics(from_str(src, "phase.s"), false);
+        assert_eq!(info.diagnostics.len(), 1);
+        assert!(info.diagnostics[0]
+            .message
+            .starts_with("phase.s:2:2: phase error: 3 bytes in pass1, 2 in pass2"));
+        // Without narrowing, the size doesn't depend on the value.
+        let src = format!("\t.zpopt off\n{src}");
+        assert_eq!(
+            assemble_str(&src, "phase.s"),
+            Ok(vec![0xAD, 0x10, 0x00, 0x60])
+        );
+    }
 }
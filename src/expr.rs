@@ -1,6 +1,6 @@
 //! Expression tree enums.
 
-use std::rc::Rc;
+use std::{rc::Rc, slice, str};
 
 use crate::{asm::Assembler, source::LineSlice};
 
@@ -17,27 +17,51 @@ impl ExprNode {
     }
 }
 
+/// A function defined by `.func`: a single expression with positional parameters.
+#[derive(PartialEq)]
+pub struct Func {
+    pub params: Vec<String>,
+    pub body: Box<ExprNode>,
+}
+
 /// A given type of expression tree node, along with its operands.
 #[derive(PartialEq)]
 pub enum ExLab {
     Name,
-    Num(u16),
+    Num(u32),
     Add(Box<ExprNode>, Box<ExprNode>),
     Sub(Box<ExprNode>, Box<ExprNode>),
     Mul(Box<ExprNode>, Box<ExprNode>),
     Div(Box<ExprNode>, Box<ExprNode>),
     Mod(Box<ExprNode>, Box<ExprNode>),
+    SDiv(Box<ExprNode>, Box<ExprNode>),
+    SMod(Box<ExprNode>, Box<ExprNode>),
     Neg(Box<ExprNode>),
     Hi(Box<ExprNode>),
     Lo(Box<ExprNode>),
     Expr(Box<ExprNode>),
     Str(String),
+    Char(u8),
     RelOp(RelOp, Box<ExprNode>, Box<ExprNode>),
     And(Box<ExprNode>, Box<ExprNode>),
     Or(Box<ExprNode>, Box<ExprNode>),
+    /// `&&`: 1 if both operands are non-zero, otherwise 0.
+    LogicalAnd(Box<ExprNode>, Box<ExprNode>),
+    /// `||`: 1 if either operand is non-zero, otherwise 0.
+    LogicalOr(Box<ExprNode>, Box<ExprNode>),
+    Ternary(Box<ExprNode>, Box<ExprNode>, Box<ExprNode>),
+    /// A `.byte`/`.word` argument repeated a number of times, as `{value} dup {count}`.
+    Dup(Box<ExprNode>, Box<ExprNode>),
+    /// A call of a `.func`, with its arguments.
+    #[allow(clippy::vec_box)]
+    Call(Rc<Func>, Vec<Box<ExprNode>>),
+    /// A `.func` parameter, by position, within the function's body.
+    Param(usize),
 }
 
 /// A relational operator.
+///
+/// The `S` variants compare their operands as signed.
 #[derive(PartialEq)]
 pub enum RelOp {
     Less,
@@ -46,15 +70,66 @@ pub enum RelOp {
     Nequ,
     LessEqu,
     GreatEqu,
+    SLess,
+    SGreat,
+    SLessEqu,
+    SGreatEqu,
 }
 
 impl ExprNode {
+    /// Evaluate the expression tree, checking it fits in a (signed or unsigned) byte.
+    pub fn eval_byte(&self, asm: &mut Assembler) -> Result<u8, String> {
+        let value = self.eval(asm)?;
+        if (i8::MIN as i32..=u8::MAX as i32).contains(&(value as i32)) {
+            Ok(value as u8)
+        } else if (value as i32) < 0 {
+            // Shown in decimal, as the two's complement hex would hide what went wrong.
+            self.slice.err(&format!(
+                "value {} doesn't fit in a byte (the lowest is -128)",
+                value as i32
+            ))
+        } else {
+            self.slice.err(&format!(
+                "value ${:X} doesn't fit in a byte (use '<' for its low byte)",
+                value
+            ))
+        }
+    }
+
+    /// Evaluate the expression tree, checking it fits in a (signed or unsigned) word.
+    pub fn eval_word(&self, asm: &mut Assembler) -> Result<u16, String> {
+        Ok(self.eval_signed_word(asm)?.0)
+    }
+
+    /// Evaluate the expression tree as a word, as `eval_word` does, also returning whether
+    /// it was negative.
+    pub fn eval_signed_word(&self, asm: &mut Assembler) -> Result<(u16, bool), String> {
+        let value = self.eval(asm)?;
+        if (i16::MIN as i32..=u16::MAX as i32).contains(&(value as i32)) {
+            Ok((value as u16, (value as i32) < 0))
+        } else {
+            self.slice
+                .err(&format!("value ${:X} doesn't fit in a word", value))
+        }
+    }
+
+    /// If this is a string (or character) literal, possibly in parentheses, return its text.
+    pub fn str_literal(&self) -> Option<&str> {
+        match &self.label {
+            ExLab::Expr(e) => e.str_literal(),
+            ExLab::Str(s) => Some(s),
+            ExLab::Char(c) => str::from_utf8(slice::from_ref(c)).ok(),
+            _ => None,
+        }
+    }
+
     /// Try to evaluate an expression tree.
-    pub fn eval(&self, asm: &mut Assembler) -> Result<u16, String> {
+    ///
+    /// Evaluation is done in 32-bits; use `eval_byte`/`eval_word` to narrow the result.
+    pub fn eval(&self, asm: &mut Assembler) -> Result<u32, String> {
         match &self.label {
             ExLab::Name => {
-                let sym = asm.lookup(self.slice.text(), self.slice.clone());
-                if let Some(value) = sym.value {
+                if let Some(value) = asm.lookup_ref(self.slice.text(), self.slice.clone()) {
                     Ok(value)
                 } else {
                     self.slice
@@ -65,17 +140,51 @@ impl ExprNode {
             ExLab::Add(left, right) => Ok(left.eval(asm)?.wrapping_add(right.eval(asm)?)),
             ExLab::Sub(left, right) => Ok(left.eval(asm)?.wrapping_sub(right.eval(asm)?)),
             ExLab::Mul(left, right) => Ok(left.eval(asm)?.wrapping_mul(right.eval(asm)?)),
-            ExLab::Div(left, right) => Ok(left.eval(asm)?.wrapping_div(right.eval(asm)?)),
-            ExLab::Mod(left, right) => Ok(left.eval(asm)?.wrapping_rem(right.eval(asm)?)),
+            ExLab::Div(left, right) => {
+                let left = left.eval(asm)?;
+                match right.eval(asm)? {
+                    0 => self.slice.err("division by zero"),
+                    right => Ok(left.wrapping_div(right)),
+                }
+            }
+            ExLab::Mod(left, right) => {
+                let left = left.eval(asm)?;
+                match right.eval(asm)? {
+                    0 => self.slice.err("division by zero"),
+                    right => Ok(left.wrapping_rem(right)),
+                }
+            }
+            ExLab::SDiv(left, right) => {
+                let left = left.eval(asm)? as i16;
+                match right.eval(asm)? as i16 {
+                    0 => self.slice.err("division by zero"),
+                    right => Ok(left.wrapping_div(right) as i32 as u32),
+                }
+            }
+            ExLab::SMod(left, right) => {
+                let left = left.eval(asm)? as i16;
+                match right.eval(asm)? as i16 {
+                    0 => self.slice.err("division by zero"),
+                    right => Ok(left.wrapping_rem(right) as i32 as u32),
+                }
+            }
             ExLab::Neg(e) => Ok(e.eval(asm)?.wrapping_neg()),
-            ExLab::Hi(e) => Ok(e.eval(asm)? >> 8),
+            ExLab::Hi(e) => Ok((e.eval(asm)? >> 8) & 0xFF),
             ExLab::Lo(e) => Ok(e.eval(asm)? & 0xFF),
             ExLab::Expr(e) => e.eval(asm),
             ExLab::Str(s) => match s.len() {
                 0 => self.slice.err("string must contain one character"),
                 2.. => self.slice.err("string must consist of one byte only"),
-                _ => Ok(s.bytes().next().unwrap() as u16),
+                _ => Ok(s.bytes().next().unwrap() as u32),
             },
+            ExLab::Char(c) => Ok(*c as u32),
+            ExLab::RelOp(rel_op @ (RelOp::Equ | RelOp::Nequ), left, right)
+                if left.str_literal().is_some() && right.str_literal().is_some() =>
+            {
+                // Two string literals are compared as strings.
+                let equ = left.str_literal() == right.str_literal();
+                Ok((equ == (*rel_op == RelOp::Equ)) as u32)
+            }
             ExLab::RelOp(rel_op, left, right) => {
                 let left = left.eval(asm)?;
                 let right = right.eval(asm)?;
@@ -86,6 +195,10 @@ impl ExprNode {
                     RelOp::Nequ => left != right,
                     RelOp::LessEqu => left <= right,
                     RelOp::GreatEqu => left >= right,
+                    RelOp::SLess => (left as i16) < (right as i16),
+                    RelOp::SGreat => (left as i16) > (right as i16),
+                    RelOp::SLessEqu => (left as i16) <= (right as i16),
+                    RelOp::SGreatEqu => (left as i16) >= (right as i16),
                 };
                 if result {
                     Ok(1)
@@ -95,6 +208,34 @@ impl ExprNode {
             }
             ExLab::And(left, right) => Ok(left.eval(asm)? & right.eval(asm)?),
             ExLab::Or(left, right) => Ok(left.eval(asm)? | right.eval(asm)?),
+            // The right operand is only evaluated if it's needed.
+            ExLab::LogicalAnd(left, right) => {
+                Ok((left.eval(asm)? != 0 && right.eval(asm)? != 0) as u32)
+            }
+            ExLab::LogicalOr(left, right) => {
+                Ok((left.eval(asm)? != 0 || right.eval(asm)? != 0) as u32)
+            }
+            ExLab::Ternary(cond, left, right) => {
+                if cond.eval(asm)? != 0 {
+                    left.eval(asm)
+                } else {
+                    right.eval(asm)
+                }
+            }
+            ExLab::Dup(..) => self
+                .slice
+                .err("'dup' is only allowed in '.byte' and '.word'"),
+            ExLab::Call(func, args) => {
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    values.push(arg.eval(asm)?);
+                }
+                asm.func_args.push(values);
+                let value = func.body.eval(asm);
+                asm.func_args.pop();
+                value
+            }
+            ExLab::Param(i) => Ok(asm.func_args.last().unwrap()[*i]),
         }
     }
 }
@@ -132,6 +273,258 @@ mod tests {
             f.clone(),
         );
         let mut a = Assembler::new(source::from_str("foo", "foo"), false);
-        assert_eq!(n.eval(&mut a), Ok(0xFFFF));
+        assert_eq!(n.eval_word(&mut a), Ok(0xFFFF));
+        assert_eq!(n.eval_byte(&mut a), Ok(0xFF));
+    }
+
+    #[test]
+    fn test_wide_eval() {
+        use crate::{assemble, assemble_str};
+
+        let src = "
+FOO     = $8000 * 4 / 8
+BAR     = ($FFFF + 1) / 2
+NEG     = -1
+        .word $8000 * 2 - 1, -32768
+        .byte 255, -128, >($1234 * $100), <$123456
+        lda #-1";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(info.symtab["FOO"].value, Some(0x4000));
+        assert_eq!(info.symtab["BAR"].value, Some(0x8000));
+        assert_eq!(info.symtab["NEG"].value, Some(0xFFFF));
+        assert_eq!(
+            info.bytes,
+            vec![0xFF, 0xFF, 0x00, 0x80, 0xFF, 0x80, 0x34, 0x56, 0xA9, 0xFF]
+        );
+
+        assert!(assemble_str("FOO = $FFFF + 1", "src").is_err());
+        assert!(assemble_str(".word $10000", "src").is_err());
+        assert!(assemble_str(".word -32769", "src").is_err());
+        assert!(assemble_str(".byte $100", "src").is_err());
+        assert!(assemble_str(".byte -129", "src").is_err());
+        assert!(assemble_str("lda #$100", "src").is_err());
+        assert!(assemble_str("lda $8000 * 2", "src").is_err());
+        assert!(assemble_str("FOO = $100000000", "src").is_err());
+    }
+
+    #[test]
+    fn test_signed() {
+        use crate::assemble;
+
+        let src = "
+ULESS   = -1 < 0
+UGREAT  = -1 > 0
+UDIV    = $FFFF / 2
+UMOD    = -7 % 2
+        .signed
+SLESS   = -1 < 0
+SGREAT  = -1 > 0
+SLE     = -1 <= -1
+SGE     = -2 >= -1
+SDIV    = $FFFF / 2
+SMOD    = -7 % 2
+        .unsigned
+ULESS2  = -1 < 0";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(info.symtab["ULESS"].value, Some(0));
+        assert_eq!(info.symtab["UGREAT"].value, Some(1));
+        assert_eq!(info.symtab["UDIV"].value, Some(0x7FFF));
+        assert_eq!(info.symtab["UMOD"].value, Some(1));
+        assert_eq!(info.symtab["SLESS"].value, Some(1));
+        assert_eq!(info.symtab["SGREAT"].value, Some(0));
+        assert_eq!(info.symtab["SLE"].value, Some(1));
+        assert_eq!(info.symtab["SGE"].value, Some(0));
+        assert_eq!(info.symtab["SDIV"].value, Some(0));
+        assert_eq!(info.symtab["SMOD"].value, Some(0xFFFF));
+        assert_eq!(info.symtab["ULESS2"].value, Some(0));
+    }
+
+    #[test]
+    fn test_logical() {
+        use crate::{assemble, assemble_str};
+
+        let src = "
+        .if (1 < 2) && (3 > 2)
+        .byte 1
+        .else
+        .byte 2
+        .endif
+        .if 1 < 2 && 3 < 2 || 0
+        .byte 3
+        .endif
+LAND    = 6 && 4
+BITAND  = 6 & 4
+LOR     = 0 || 4
+BITOR   = 2 | 4
+PREC    = 1 || 0 && 0
+SHORT   = 0 && undefined
+        .byte 0 || 0, 5 && $100";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(info.bytes, vec![1, 0, 1]);
+        assert_eq!(info.symtab["LAND"].value, Some(1));
+        assert_eq!(info.symtab["BITAND"].value, Some(4));
+        assert_eq!(info.symtab["LOR"].value, Some(1));
+        assert_eq!(info.symtab["BITOR"].value, Some(6));
+        assert_eq!(info.symtab["PREC"].value, Some(1));
+        assert_eq!(info.symtab["SHORT"].value, Some(0));
+        assert!(assemble_str("FOO = 1 && undefined", "src").is_err());
+    }
+
+    #[test]
+    fn test_builtins() {
+        use crate::{assemble, assemble_str};
+
+        let src = "
+        .byte 0
+        .byte 1
+
+        .byte .line, .LINE + 1
+        .word .file";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(info.bytes[2..4], [5, 6]);
+        let a = assemble_str(".word .file", "a.s").unwrap();
+        let b = assemble_str(".word .file", "b.s").unwrap();
+        assert_ne!(a, b);
+        assert_eq!(
+            info.bytes[4..],
+            assemble_str(".word .file", "src").unwrap()[..]
+        );
+        assert!(assemble_str(".byte .nope", "src").is_err());
+        assert!(assemble_str("line = 1\n.byte .line", "src").is_ok());
+    }
+
+    #[test]
+    fn test_defined() {
+        use crate::{assemble, assemble_str};
+
+        let src = "
+FOO     = 5
+        .if .defined(FOO) && FOO > 3
+        .byte 1
+        .endif
+        .if .defined(BAR) && BAR > 3
+        .byte 2
+        .endif
+        .byte .defined ( FOO ), .DEFINED(LATER), .defined(BAR) + 2
+LATER   .byte .defined(LATER)
+        .byte .defined(LATER)";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(info.bytes, vec![1, 1, 0, 2, 0, 1]);
+        // Only the guarded reference notices BAR.
+        assert_eq!(info.symtab["BAR"].references().count(), 1);
+        assert!(assemble_str(".byte .defined FOO", "src").is_err());
+        assert!(assemble_str(".byte .defined(1)", "src").is_err());
+    }
+
+    #[test]
+    fn test_div_zero() {
+        use crate::assemble_str;
+
+        assert!(assemble_str("FOO = 1 / 0", "src").is_err());
+        assert!(assemble_str("FOO = 1 % 0", "src").is_err());
+        assert!(assemble_str("A = 2\nFOO = 7 / (A - A)", "src").is_err());
+        assert_eq!(assemble_str(".byte 7 / 2, 7 % 2", "src"), Ok(vec![3, 1]));
+    }
+
+    #[test]
+    fn test_ternary() {
+        use crate::{assemble, assemble_str};
+
+        let src = "
+BIG     = 1
+SMALL   = 0
+FOO     = BIG ? $4000 : $2000
+BAR     = SMALL ? $4000 : $2000
+FOOBAR  = SMALL ? 1 : BIG ? 2 : 3
+        .byte BIG=1 ? 'Y' : 'N', <(FOO + 1) ? 5 : 6";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(info.symtab["FOO"].value, Some(0x4000));
+        assert_eq!(info.symtab["BAR"].value, Some(0x2000));
+        assert_eq!(info.symtab["FOOBAR"].value, Some(2));
+        assert_eq!(info.bytes, vec![b'Y', 5]);
+        assert!(assemble_str("FOO = 1 ? 2", "src").is_err());
+    }
+
+    #[test]
+    fn test_char_literal() {
+        use crate::assemble_str;
+
+        let src = "
+CHAR    = 'A' + 1
+        lda #'A'
+        lda #CHAR
+        .byte '\\n' * 2, \"Z\" - 'A'";
+        assert_eq!(
+            assemble_str(src, "src"),
+            Ok(vec![0xA9, 65, 0xA9, 66, 20, 25])
+        );
+        assert!(assemble_str("FOO = 'AB' + 1", "src").is_err());
+        assert_eq!(assemble_str(".pstr 'A'", "src"), Ok(vec![1, 65]));
+    }
+
+    #[test]
+    fn test_str_escapes() {
+        use crate::assemble_str;
+
+        assert_eq!(assemble_str("FOO = '\\n'\n.byte FOO", "src"), Ok(vec![10]));
+        assert_eq!(assemble_str(".byte \"\\x41B\"", "src"), Ok(vec![65, 66]));
+        assert_eq!(
+            assemble_str(r#".byte '\r\t\0\\\'\"', "\"'""#, "src"),
+            Ok(vec![13, 9, 0, b'\\', b'\'', b'"', b'"', b'\''])
+        );
+        assert!(assemble_str(".byte '\\q'", "src").is_err());
+        assert!(assemble_str(".byte '\\x4'", "src").is_err());
+        assert!(assemble_str(".byte '\\xFF'", "src").is_err());
+    }
+
+    #[test]
+    fn test_digit_separators() {
+        use crate::assemble_str;
+
+        assert_eq!(
+            assemble_str(".byte %1111_0000, @3_7\n.word $12_34, 1_000", "src"),
+            Ok(vec![0xF0, 0o37, 0x34, 0x12, 0xE8, 0x03])
+        );
+        assert_eq!(assemble_str(".byte %1_0_1, 2_5_5", "src"), Ok(vec![5, 255]));
+        assert!(assemble_str(".byte %1111__0000", "src").is_err());
+        assert!(assemble_str(".byte %1111_", "src").is_err());
+        assert!(assemble_str(".byte $_12", "src").is_err());
+        assert!(assemble_str(".byte 1_ + 2", "src").is_err());
+    }
+
+    #[test]
+    fn test_c_prefixes() {
+        use crate::assemble_str;
+
+        assert_eq!(
+            assemble_str(".byte 0xFF, 0Xa, 0b1010, 0B1\n.word 0x12_34", "src"),
+            assemble_str(".byte $FF, $A, 10, 1\n.word $1234", "src")
+        );
+        assert_eq!(
+            assemble_str(".byte 0, 07, 0+1, %0, $0B", "src"),
+            Ok(vec![0, 7, 1, 0, 11])
+        );
+        assert!(assemble_str(".byte 0x", "src").is_err());
+        assert!(assemble_str(".byte $", "src").is_err());
+        assert!(assemble_str(".byte 0b2", "src").is_err());
+    }
+
+    #[test]
+    fn test_trailing_radix() {
+        use crate::assemble_str;
+
+        assert_eq!(
+            assemble_str(".byte 0FFh, 10H, 1010b, 0b, 1_0B, 0bh\n.word 1234h", "src"),
+            Ok(vec![0xFF, 0x10, 10, 0, 2, 0x0B, 0x34, 0x12])
+        );
+        // Not all binary digits, so a decimal number with a bad digit.
+        assert!(assemble_str(".byte 12b", "src").is_err());
+        assert!(assemble_str(".byte 0FGh", "src").is_err());
+        // Without a leading digit, it's a name.
+        assert_eq!(
+            assemble_str("FFh = 3\n.byte FFh, 0FFh", "src"),
+            Ok(vec![3, 0xFF])
+        );
+        assert!(assemble_str(".byte 12foo", "src").is_err());
     }
 }
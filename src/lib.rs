@@ -1,10 +1,12 @@
 //! Pop65: a simple 6502 assembler.
 
-use std::{collections::HashMap, mem};
+use std::{collections::HashMap, fmt, mem, rc::Rc};
 
+pub use asm::AsmError;
 use asm::Assembler;
-pub use source::from_file;
+pub use opcode::Cpu;
 use source::Source;
+pub use source::{from_file, from_resolver, FileResolver, FsResolver};
 pub use symbol::Symbol;
 
 /// Assemble code from a source string.
@@ -18,11 +20,53 @@ pub fn assemble_str(src: &str, path: &str) -> Result<Vec<u8>, String> {
 pub struct AsmInfo {
     pub bytes: Vec<u8>,
     pub symtab: HashMap<String, Box<Symbol>>,
+    /// The default debug stream's output.
     pub debug_str: String,
+    /// The output of each named debug stream (as set by `.dbg {name}, {string}`), by name.
+    pub debug_streams: HashMap<String, String>,
     pub listing: Option<String>,
+    /// The cold start entry point, as set by `.coldstart`.
+    pub cold_start: Option<u16>,
+    /// The warm start entry point, as set by `.warmstart`.
+    pub warm_start: Option<u16>,
+    /// Any warnings issued during assembly.
+    pub warnings: Vec<String>,
+    /// The output bytes, split into contiguous regions with their load addresses.
+    pub segments: Vec<(u16, Vec<u8>)>,
+    /// Every error and warning issued during assembly, in order.
+    pub diagnostics: Vec<AsmError>,
+    /// The comment for each macro that has one (from the comment lines above its `.mac`, or
+    /// on the same line), by name.
+    pub macro_comments: HashMap<String, String>,
+    /// The total base cycle count of every instruction assembled, not counting taken
+    /// branches or page crossings.
+    pub cycles: u32,
+    /// The report of each `.cycles` range: its position, addresses, and base cycle count.
+    pub cycle_reports: Vec<String>,
 }
 
 impl AsmInfo {
+    /// Return the address the first output byte loads at, which is the binary's base: set by
+    /// whatever `.org` is in effect then, not padded out from 0.
+    pub fn load_address(&self) -> Option<u16> {
+        self.segments.first().map(|(start, _)| *start)
+    }
+
+    /// Return the address label with the greatest value at or below `addr`, and how far past
+    /// it `addr` is, so `$8010` can be shown as `start+16`. As in the debug file, only labels
+    /// count, not symbols defined with `=` or `.set`, or struct members. Of several labels
+    /// with the same value, the first by name is returned.
+    pub fn symbol_at(&self, addr: u16) -> Option<(&str, u16)> {
+        let (value, name) = self
+            .symtab
+            .values()
+            .filter(|s| s.label)
+            .filter_map(|s| Some((s.value?, s.name.as_str())))
+            .filter(|(value, _)| *value <= addr)
+            .max_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(a.1)))?;
+        Some((name, addr - value))
+    }
+
     /// Dump the symbol table to a sorted string.
     pub fn dump_symtab(&self) -> String {
         let mut symstr = String::new();
@@ -33,30 +77,252 @@ impl AsmInfo {
         }
         symstr
     }
+
+    /// Dump the symbol table to a string sorted by name, whatever the values, so it diffs
+    /// well between builds.
+    pub fn dump_symtab_by_name(&self) -> String {
+        let mut symstr = String::new();
+        let mut symbols = Vec::from_iter(self.symtab.values());
+        symbols.sort_by(|a, b| a.name.cmp(&b.name));
+        for symbol in symbols {
+            symstr.push_str(&format!("{}\n", symbol));
+        }
+        symstr
+    }
+
+    /// Dump only the symbols marked with `.global`/`.export`, in the same format and order
+    /// as `dump_symtab`.
+    pub fn dump_exports(&self) -> String {
+        let mut symstr = String::new();
+        let mut symbols = Vec::from_iter(self.symtab.values().filter(|s| s.exported));
+        symbols.sort();
+        for symbol in symbols {
+            symstr.push_str(&format!("{}\n", symbol));
+        }
+        symstr
+    }
+
+    /// Dump the defined symbols as VICE monitor labels (`al C:{addr} .{name}`), sorted by
+    /// address.
+    pub fn dump_vice_labels(&self) -> String {
+        let mut symbols = Vec::from_iter(
+            self.symtab
+                .values()
+                .filter_map(|s| Some((s.value?, &s.name))),
+        );
+        symbols.sort();
+        let mut labels = String::new();
+        for (value, name) in symbols {
+            labels.push_str(&format!("al C:{:04x} .{}\n", value, name));
+        }
+        labels
+    }
+
+    /// Dump a map of the output: each segment's start and end address and size, any gaps
+    /// between them, the total size, and the number of symbols.
+    pub fn dump_map(&self) -> String {
+        let mut map = "START END  SIZE\n".to_string();
+        let mut last_end: Option<u16> = None;
+        for (start, bytes) in &self.segments {
+            let end = start.wrapping_add((bytes.len() - 1) as u16);
+            if let Some(last_end) = last_end {
+                if *start > last_end.wrapping_add(1) {
+                    map.push_str(&format!("GAP        {}\n", start - last_end - 1));
+                }
+            }
+            map.push_str(&format!("{:04X}  {:04X} {}\n", start, end, bytes.len()));
+            last_end = Some(end);
+        }
+        let symbols = self.symtab.values().filter(|s| s.value.is_some()).count();
+        map.push_str(&format!("\nTOTAL      {}\n", self.bytes.len()));
+        map.push_str(&format!("SYMBOLS    {}\n", symbols));
+        map
+    }
+
+    /// Dump the symbol table and segment layout as JSON, for other tools to read.
+    pub fn dump_json(&self) -> String {
+        output::json(self)
+    }
+
+    /// Dump the output bytes as a hex dump, like `xxd`: each line with its address, the bytes
+    /// in hex, and those bytes as ASCII.
+    pub fn dump_hex(&self) -> String {
+        output::hexdump(&self.segments)
+    }
+}
+
+/// Assemble a source file, returning each contiguous region of output bytes with its load
+/// address.
+///
+/// A new region starts whenever the bytes stop being contiguous, as after an `.org`.
+pub fn assemble_segments(src: Source) -> Result<Vec<(u16, Vec<u8>)>, String> {
+    Ok(assemble(src, false)?.segments)
+}
+
+/// Options for assembling, beyond the source itself.
+#[derive(Clone)]
+pub struct AsmOptions {
+    /// Set to produce a listing, in `AsmInfo::listing`.
+    pub listing: bool,
+    /// Set for the listing to show each instruction disassembled, beside its bytes.
+    pub listing_disasm: bool,
+    /// How many bytes the listing shows on each line, before continuing on the next; at
+    /// least 1.
+    pub listing_bytes: usize,
+    /// The setting of `.zpopt` at the start of assembly; if false, absolute addresses are never
+    /// narrowed to zero page ones unless the source says so.
+    pub zp_opt: bool,
+    /// The CPU whose instructions are available at the start of assembly, until a `.cpu`.
+    pub cpu: Cpu,
+    /// Reads the files named by `.inc`, `.incbin`, and `filesize`; the real filesystem by
+    /// default.
+    pub resolver: Rc<dyn FileResolver>,
+    /// The paths of files of `{name}={expr}` lines, predefining a symbol for each before
+    /// assembly.
+    pub define_files: Vec<String>,
+    /// If set, the output is padded to this many bytes with `rom_fill`, as for a ROM image;
+    /// it's an error if there's already more.
+    pub rom_size: Option<usize>,
+    /// The byte to pad a ROM image with: by default `$FF`, as in an unprogrammed EPROM.
+    pub rom_fill: u8,
+}
+
+impl Default for AsmOptions {
+    fn default() -> Self {
+        Self {
+            listing: false,
+            listing_disasm: false,
+            listing_bytes: asm::BYTES_PER_LINE,
+            zp_opt: true,
+            cpu: Cpu::Mos6502,
+            resolver: Rc::new(FsResolver),
+            define_files: Vec::new(),
+            rom_size: None,
+            rom_fill: 0xFF,
+        }
+    }
+}
+
+impl fmt::Debug for AsmOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsmOptions")
+            .field("listing", &self.listing)
+            .field("listing_disasm", &self.listing_disasm)
+            .field("listing_bytes", &self.listing_bytes)
+            .field("zp_opt", &self.zp_opt)
+            .field("cpu", &self.cpu)
+            .field("define_files", &self.define_files)
+            .field("rom_size", &self.rom_size)
+            .field("rom_fill", &self.rom_fill)
+            .finish_non_exhaustive()
+    }
 }
 
 /// Assemble a source file.
 pub fn assemble(src: Source, listing: bool) -> Result<AsmInfo, String> {
-    let mut asm = Box::new(Assembler::new(src, listing));
-    asm.pass1()?;
-    let bytes = asm.pass2()?;
-    let listing = {
-        if let Some(lines) = asm.listing.as_ref() {
-            let mut s = "LINENO PC   BYTES  LINE\n".to_string();
-            for line in lines {
-                s.push_str(&format!("{}\n", line.trim()));
-            }
-            Some(s)
-        } else {
-            None
+    assemble_with(
+        src,
+        &AsmOptions {
+            listing,
+            ..AsmOptions::default()
+        },
+    )
+}
+
+/// Assemble a source file with the given options.
+pub fn assemble_with(src: Source, options: &AsmOptions) -> Result<AsmInfo, String> {
+    assemble_with_imports(src, Vec::new(), options)
+}
+
+/// Assemble a source file, predefining the symbols from symbol table dumps (as output by
+/// `AsmInfo::dump_symtab`).
+///
+/// Defining an imported symbol again is an error.
+pub fn assemble_with_imports(
+    src: Source,
+    imports: Vec<Source>,
+    options: &AsmOptions,
+) -> Result<AsmInfo, String> {
+    let mut asm = assembler(src, options);
+    asm.imports = imports.into_iter().flatten().collect();
+    let (info, err) = run(&mut asm);
+    match err {
+        Some(err) => Err(err),
+        None => Ok(info),
+    }
+}
+
+/// Assemble a source file, returning the info even if there were errors.
+///
+/// The errors are in `AsmInfo::diagnostics`; if there were any, there are no output bytes,
+/// segments, or listing.
+pub fn assemble_with_diagnostics(src: Source, listing: bool) -> AsmInfo {
+    let options = AsmOptions {
+        listing,
+        ..AsmOptions::default()
+    };
+    run(&mut assembler(src, &options)).0
+}
+
+/// Construct an assembler for the source, set up with the options.
+fn assembler(src: Source, options: &AsmOptions) -> Box<Assembler> {
+    let mut asm = Box::new(Assembler::new(src, options.listing));
+    asm.zp_opt_default = options.zp_opt;
+    asm.cpu_default = options.cpu;
+    asm.list_disasm = options.listing_disasm;
+    asm.list_bytes = options.listing_bytes.max(1);
+    asm.resolver = options.resolver.clone();
+    asm.define_files = options.define_files.clone();
+    asm.rom_size = options.rom_size;
+    asm.rom_fill = options.rom_fill;
+    asm
+}
+
+/// Run both passes, returning the info and the error summary (if there were errors).
+fn run(asm: &mut Assembler) -> (AsmInfo, Option<String>) {
+    let (bytes, err) = match asm.pass1().and_then(|_| asm.pass2()) {
+        Ok(bytes) => (bytes, None),
+        Err(err) => {
+            asm.segments.clear();
+            (Vec::new(), Some(err))
         }
     };
-    Ok(AsmInfo {
+    let mut debug_streams = mem::take(&mut asm.debug_str);
+    let mut info = AsmInfo {
         bytes,
         symtab: mem::take(&mut asm.symtab),
-        debug_str: mem::take(&mut asm.debug_str),
-        listing,
-    })
+        debug_str: debug_streams.remove("").unwrap_or_default(),
+        debug_streams,
+        listing: None,
+        cold_start: asm.cold_start,
+        warm_start: asm.warm_start,
+        warnings: mem::take(&mut asm.warnings),
+        segments: mem::take(&mut asm.segments),
+        diagnostics: mem::take(&mut asm.diagnostics),
+        macro_comments: asm
+            .macros
+            .iter()
+            .filter_map(|(name, mac)| Some((name.clone(), mac.comment()?.to_string())))
+            .collect(),
+        cycles: asm.cycle_log.iter().map(|(_, cycles)| cycles).sum(),
+        cycle_reports: mem::take(&mut asm.cycle_reports),
+    };
+    if let (Some(lines), None) = (asm.listing.as_ref(), &err) {
+        let mut s = format!("LINENO PC   {:1$} ", "BYTES", asm.list_bytes * 2);
+        if asm.list_disasm {
+            s.push_str("DISASM            ");
+        }
+        s.push_str("LINE\n");
+        for line in lines {
+            // A form feed is kept, for a page break.
+            let line = line.trim_end_matches(|c: char| c != '\x0C' && c.is_whitespace());
+            s.push_str(&format!("{}\n", line));
+        }
+        s.push_str("\nSYMBOLS\n");
+        s.push_str(&info.dump_symtab());
+        info.listing = Some(s);
+    }
+    (info, err)
 }
 
 mod action;
@@ -64,6 +330,7 @@ mod asm;
 mod expr;
 mod mac;
 mod opcode;
+mod output;
 mod parse;
 mod pseudo;
 mod source;
@@ -71,9 +338,13 @@ mod symbol;
 
 #[cfg(test)]
 mod tests {
-    use std::fs;
+    use std::{collections::HashMap, fs, rc::Rc};
 
-    use crate::{assemble, assemble_str, from_file, source};
+    use crate::{
+        assemble, assemble_segments, assemble_str, assemble_with, assemble_with_diagnostics,
+        assemble_with_imports, assembler, from_file, run, source, source::MemFiles, AsmOptions,
+        Cpu,
+    };
 
     #[test]
     fn test_asm_str() {
@@ -126,6 +397,136 @@ print2  rts";
         assert_eq!(info.bytes, vec![0x36, 0x12, 0x34, 0x12])
     }
 
+    #[test]
+    fn test_relative_include() {
+        let info = assemble(from_file("inc_dir/main.s").unwrap(), false).unwrap();
+        assert_eq!(info.bytes, vec![0x00, 0x20, 0x42, b'A', b'B', 2]);
+        let deep = info.symtab["deep"]
+            .defined_at()
+            .unwrap()
+            .path()
+            .replace('\\', "/");
+        assert_eq!(deep, "inc_dir/nested/deep.s");
+    }
+
+    #[test]
+    fn test_resolver() {
+        let files = MemFiles(HashMap::from([
+            ("sprites.bin", &[1, 2, 3][..]),
+            ("lib/consts.s", b"SIZE = filesize(\"sprites.bin\")"),
+        ]));
+        let options = AsmOptions {
+            resolver: Rc::new(files),
+            ..AsmOptions::default()
+        };
+        let src = "        .inc \"lib/consts.s\"
+        .incbin \"sprites.bin\"
+        .byte SIZE";
+        let info = assemble_with(source::from_str(src, "mem.s"), &options).unwrap();
+        assert_eq!(info.bytes, vec![1, 2, 3, 3]);
+        // Nothing is read from the real filesystem.
+        let missing = assemble_with(source::from_str(".incbin \"test.s\"", "mem.s"), &options);
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn test_define_file() {
+        let defines = |text: &'static str| AsmOptions {
+            resolver: Rc::new(MemFiles(HashMap::from([("config.def", text.as_bytes())]))),
+            define_files: vec!["config.def".to_string()],
+            ..AsmOptions::default()
+        };
+        let options = defines("# Memory map\n\nBASE=$C000\nCOUNT = BASE / $1000 + 4\n");
+        let src = "        .org BASE\n        .byte COUNT";
+        let info = assemble_with(source::from_str(src, "def.s"), &options).unwrap();
+        assert_eq!(info.segments, vec![(0xC000, vec![0x10])]);
+        assert_eq!(
+            info.symtab["BASE"].defined_at().unwrap().pos(),
+            "config.def:3:1"
+        );
+
+        let options = defines("BASE=$C000\nCOUNT=BASE/\n");
+        let diagnostics = run(&mut assembler(source::from_str(src, "def.s"), &options))
+            .0
+            .diagnostics;
+        assert!(diagnostics[0].message.starts_with("config.def:2:"));
+        let options = defines("        lda #1\n");
+        let diagnostics = run(&mut assembler(source::from_str(src, "def.s"), &options))
+            .0
+            .diagnostics;
+        assert!(diagnostics[0]
+            .message
+            .starts_with("config.def:1: expected '{name}={expr}'"));
+    }
+
+    #[test]
+    fn test_rom_size() {
+        let rom = |rom_size, rom_fill| {
+            let options = AsmOptions {
+                rom_size: Some(rom_size),
+                rom_fill,
+                ..AsmOptions::default()
+            };
+            let src = "        .org $e000\n        .byte 1,2,3,4,5,6,7,8,9,10";
+            assemble_with(source::from_str(src, "rom.s"), &options)
+        };
+        let info = rom(16, 0xFF).unwrap();
+        assert_eq!(info.bytes.len(), 16);
+        assert_eq!(info.bytes[9..], [10, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(info.segments[0].1, info.bytes);
+        assert_eq!(rom(12, 0).unwrap().bytes[10..], [0, 0]);
+        assert_eq!(rom(10, 0xFF).unwrap().bytes.len(), 10);
+        assert!(rom(8, 0xFF).is_err());
+
+        // Segments are placed by address, with the gaps between them filled.
+        let options = AsmOptions {
+            rom_size: Some(8192),
+            ..AsmOptions::default()
+        };
+        let src = "        .org $e000
+reset   sei
+        cld
+        jmp reset
+        .vectors reset, reset, reset";
+        let info = assemble_with(source::from_str(src, "rom.s"), &options).unwrap();
+        assert_eq!(info.bytes.len(), 8192);
+        assert_eq!(info.bytes[..6], [0x78, 0xD8, 0x4C, 0x00, 0xE0, 0xFF]);
+        assert!(info.bytes[5..0x1FFA].iter().all(|b| *b == 0xFF));
+        assert_eq!(info.bytes[0x1FFA..], [0x00, 0xE0, 0x00, 0xE0, 0x00, 0xE0]);
+        assert_eq!(info.segments, vec![(0xE000, info.bytes.clone())]);
+        // The vectors are past the end of a smaller ROM, and code below the first segment
+        // is before its start.
+        let options = AsmOptions {
+            rom_size: Some(4096),
+            ..AsmOptions::default()
+        };
+        let err = assemble_with(source::from_str(src, "rom.s"), &options).err();
+        assert_eq!(
+            err.as_deref(),
+            Some("output at FFFA-FFFF is outside the 4096-byte ROM starting at E000")
+        );
+        let src = "        .org $e000\n        nop\n        .org $d000\n        nop";
+        assert!(assemble_with(source::from_str(src, "rom.s"), &options).is_err());
+    }
+
+    #[test]
+    fn test_symbol_at() {
+        let src = "        .org $8000
+start   .ds $20
+loop    nop
+LOOP_ALIAS = loop
+        .set COUNTER, $8021
+        .struct point
+x       .byte
+        .endstruct";
+        let info = assemble(source::from_str(src, "at.s"), false).unwrap();
+        assert_eq!(info.symbol_at(0x8010), Some(("start", 16)));
+        assert_eq!(info.symbol_at(0x8000), Some(("start", 0)));
+        assert_eq!(info.symbol_at(0x8021), Some(("loop", 1)));
+        assert_eq!(info.symbol_at(0x0000), None);
+        assert_eq!(info.symbol_at(0x7FFF), None);
+    }
+
     #[test]
     fn test_dbg() {
         let src = "
@@ -138,6 +539,27 @@ foo     .word foo";
         assert_eq!(&info.debug_str, "P:14000:foo\n");
     }
 
+    #[test]
+    fn test_dbg_streams() {
+        let src = "
+        .org $8000
+        .dbg vice, 'al C:{V:4} .{L}'
+        .dbg mlb, 'P:{V-8000}:{L}'
+foo     nop
+        .dbg mlb
+        .dbg '{L}'
+bar     nop";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(info.debug_streams.len(), 2);
+        assert_eq!(
+            info.debug_streams["vice"],
+            "al C:8000 .foo\nal C:8001 .bar\n"
+        );
+        assert_eq!(info.debug_streams["mlb"], "P:0:foo\n");
+        assert_eq!(&info.debug_str, "bar\n");
+        assert!(!info.symtab.contains_key("vice"));
+    }
+
     #[test]
     fn test_bin() {
         let foo: [u8; 4] = [1, 2, 3, 4];
@@ -182,6 +604,367 @@ foobar  .word *     ; foobar's comment
         )
     }
 
+    #[test]
+    fn test_entry_points() {
+        let src = "
+        .org $8000
+        .coldstart
+cold    jmp warm
+        .warmstart warm
+warm    rts";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(info.cold_start, Some(0x8000));
+        assert_eq!(info.warm_start, Some(0x8003));
+
+        let info = assemble(source::from_str("nop", "src"), false).unwrap();
+        assert_eq!(info.cold_start, None);
+        assert_eq!(info.warm_start, None);
+        assert!(assemble_str(".coldstart 1\n.coldstart 2", "src").is_err());
+    }
+
+    #[test]
+    fn test_import_symbols() {
+        let a = "
+        .org $C000
+; prints a character
+chrout  rts
+chrin   rts
+";
+        let a_info = assemble(source::from_str(a, "a.s"), false).unwrap();
+        let a_sym = a_info.dump_symtab() + "\nundef\n";
+
+        let b = "
+        jsr chrout
+        jmp chrin";
+        let b_info = assemble_with_imports(
+            source::from_str(b, "b.s"),
+            vec![source::from_str(&a_sym, "a.sym")],
+            &AsmOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(b_info.bytes, vec![0x20, 0x00, 0xC0, 0x4C, 0x01, 0xC0]);
+        assert_eq!(b_info.symtab["chrout"].value, Some(0xC000));
+        assert_eq!(
+            b_info.symtab["chrout"].comment.as_deref().map(str::trim),
+            Some("prints a character")
+        );
+        assert!(!b_info.symtab.contains_key("undef"));
+        assert_eq!(b_info.dump_symtab(), a_info.dump_symtab());
+
+        let conflict = "chrout rts";
+        assert!(assemble_with_imports(
+            source::from_str(conflict, "b.s"),
+            vec![source::from_str(&a_sym, "a.sym")],
+            &AsmOptions::default()
+        )
+        .is_err());
+        assert!(assemble_with_imports(
+            source::from_str(b, "b.s"),
+            vec![source::from_str("chrout : XYZ", "a.sym")],
+            &AsmOptions::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_listing_symbols() {
+        let src = "start   lda #0
+        .mac nop2
+        nop
+        nop
+        .endm
+loop    jmp start";
+        let info = assemble(source::from_str(src, "sym.s"), true).unwrap();
+        assert!(info
+            .listing
+            .unwrap()
+            .ends_with("\n\nSYMBOLS\nstart : 0000\nloop : 0002\n"));
+    }
+
+    #[test]
+    fn test_options() {
+        let options = AsmOptions {
+            listing: true,
+            ..AsmOptions::default()
+        };
+        let old = assemble(from_file("test.s").unwrap(), true).unwrap();
+        let new = assemble_with(from_file("test.s").unwrap(), &options).unwrap();
+        assert_eq!(new.bytes, old.bytes);
+        assert_eq!(new.listing, old.listing);
+        assert!(new.listing.is_some());
+        assert_eq!(new.dump_symtab(), old.dump_symtab());
+        assert_eq!(AsmOptions::default().cpu, Cpu::Mos6502);
+        assert!(AsmOptions::default().zp_opt);
+    }
+
+    #[test]
+    fn test_listing_disasm() {
+        let options = AsmOptions {
+            listing: true,
+            listing_disasm: true,
+            ..AsmOptions::default()
+        };
+        let src = "        .org $1000
+        lda $1234
+        .byte 1
+        .longbranch on
+        beq *";
+        let info = assemble_with(source::from_str(src, "dis.s"), &options).unwrap();
+        assert_eq!(
+            info.listing.unwrap(),
+            "LINENO PC   BYTES  DISASM            LINE
+000001 0000                                  .org $1000
+000002 1000 AD3412 LDA $1234                 lda $1234
+000003 1003 01                               .byte 1
+000004 1004                                  .longbranch on
+000005 1004 D0034C BNE $1009 : JMP $1004         beq *
+       1007 0410
+
+SYMBOLS
+"
+        );
+    }
+
+    #[test]
+    fn test_listing_bytes() {
+        let options = AsmOptions {
+            listing: true,
+            listing_bytes: 8,
+            ..AsmOptions::default()
+        };
+        let src = "        .byte 1,2,3,4,5,6,7,8
+        .byte 9,10,11,12,13,14,15,16,17
+        rts";
+        let info = assemble_with(source::from_str(src, "bytes.s"), &options).unwrap();
+        assert_eq!(
+            info.listing.unwrap(),
+            "LINENO PC   BYTES            LINE
+000001 0000 0102030405060708         .byte 1,2,3,4,5,6,7,8
+000002 0008 090A0B0C0D0E0F10         .byte 9,10,11,12,13,14,15,16,17
+       0010 11
+000003 0011 60                       rts
+
+SYMBOLS
+"
+        );
+    }
+
+    #[test]
+    fn test_cpu_default() {
+        let asm = |src: &str, cpu| {
+            let options = AsmOptions {
+                cpu,
+                ..AsmOptions::default()
+            };
+            assemble_with(source::from_str(src, "cpu.s"), &options)
+        };
+        assert_eq!(asm("save phx", Cpu::Wdc65C02).unwrap().bytes, vec![0xDA]);
+        // On its own, it would just be a label.
+        assert!(asm("save phx", Cpu::Mos6502).is_err());
+        assert_eq!(
+            asm(
+                "stz $10\nstz $1234,x\ntsb $10\ntrb $1234\nply",
+                Cpu::Wdc65C02
+            )
+            .unwrap()
+            .bytes,
+            vec![0x64, 0x10, 0x9E, 0x34, 0x12, 0x04, 0x10, 0x1C, 0x34, 0x12, 0x7A]
+        );
+        // The source can still switch.
+        assert!(asm(".cpu \"6502\"\nsave phx", Cpu::Wdc65C02).is_err());
+        assert_eq!(
+            asm(".cpu \"65c02\"\nplx", Cpu::Mos6502).unwrap().bytes,
+            vec![0xFA]
+        );
+        assert_eq!(Cpu::from_name("65C02"), Some(Cpu::Wdc65C02));
+        assert_eq!(Cpu::from_name("65816"), None);
+    }
+
+    #[test]
+    fn test_zp_opt() {
+        let src = "lda $10";
+        let on = assemble_with(source::from_str(src, "zp.s"), &AsmOptions::default());
+        assert_eq!(on.unwrap().bytes, vec![0xA5, 0x10]);
+        let options = AsmOptions {
+            zp_opt: false,
+            ..AsmOptions::default()
+        };
+        let off = assemble_with(source::from_str(src, "zp.s"), &options);
+        assert_eq!(off.unwrap().bytes, vec![0xAD, 0x10, 0x00]);
+
+        let src = "lda $10,x
+        .zpopt off
+        lda $10,x
+        ldx $10,y
+        .zpopt on
+        ldx $10,y";
+        assert_eq!(
+            assemble_str(src, "zp.s").unwrap(),
+            vec![0xB5, 0x10, 0xBD, 0x10, 0x00, 0xBE, 0x10, 0x00, 0xB6, 0x10]
+        );
+    }
+
+    #[test]
+    fn test_segments() {
+        let src = "        .org $C000
+        lda #1
+        .pushpc $0200
+        rts
+        .poppc
+        .org $E000
+        .org $FFFC
+        .word $C000";
+        assert_eq!(
+            assemble_segments(source::from_str(src, "seg.s")).unwrap(),
+            vec![(0xC000, vec![0xA9, 1, 0x60]), (0xFFFC, vec![0x00, 0xC0])]
+        );
+        assert_eq!(
+            assemble_segments(source::from_str(".org $10\n.org $20", "seg.s")).unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_org_base() {
+        let src = "        .org $8000
+start   .word *
+        lda #1
+        .org $9000
+        .byte <*, >*";
+        let info = assemble(source::from_str(src, "org.s"), false).unwrap();
+        assert_eq!(info.symtab["start"].value, Some(0x8000));
+        assert_eq!(info.bytes, vec![0x00, 0x80, 0xA9, 1, 0x00, 0x90]);
+        assert_eq!(info.load_address(), Some(0x8000));
+        assert_eq!(
+            assemble(source::from_str(".org $10", "org.s"), false)
+                .unwrap()
+                .load_address(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let src = "        lda #1
+        foo bar
+        .checkpage 0
+        lda ($10),z";
+        let info = assemble_with_diagnostics(source::from_str(src, "diag.s"), true);
+        assert!(info.bytes.is_empty());
+        assert!(info.listing.is_none());
+        assert_eq!(info.diagnostics.len(), 2);
+        assert!(info.diagnostics.iter().all(|d| !d.warning));
+        assert!(info.diagnostics[0].message.starts_with("diag.s:2:"));
+        assert!(info.diagnostics[1].message.starts_with("diag.s:4:"));
+
+        let info = assemble_with_diagnostics(
+            source::from_str(".warnpage on\n.org $FF\nlda $1234", "diag.s"),
+            false,
+        );
+        assert_eq!(info.bytes, vec![0xAD, 0x34, 0x12]);
+        assert_eq!(info.diagnostics.len(), 1);
+        assert!(info.diagnostics[0].warning);
+    }
+
+    #[test]
+    fn test_symtab_by_name() {
+        let src = "zeta    = 1
+alpha   = 1
+mid     = 0
+beta    nop";
+        let info = assemble(source::from_str(src, "sym.s"), false).unwrap();
+        assert_eq!(
+            info.dump_symtab_by_name(),
+            "alpha : 0001\nbeta : 0000\nmid : 0000\nzeta : 0001\n"
+        );
+    }
+
+    #[test]
+    fn test_exports() {
+        let src = "        .global init, play
+        .org $1000
+init    rts             ; set up
+helper  rts
+        .scope sound
+        .export play
+play    rts
+        .endscope
+play    = 3";
+        let info = assemble(source::from_str(src, "exp.s"), false).unwrap();
+        assert_eq!(
+            info.dump_exports(),
+            "play : 0003\ninit : 1000 : set up\nsound.play : 1002\n"
+        );
+        assert!(!info.symtab["helper"].exported);
+        assert!(assemble(source::from_str(".global nowhere", "exp.s"), false).is_err());
+        assert!(assemble(source::from_str(".global 1", "exp.s"), false).is_err());
+    }
+
+    #[test]
+    fn test_vice_labels() {
+        let src = "        .org $8000
+start   nop
+zp      = $fb
+        .byte undef
+undef   = 2";
+        let info = assemble(source::from_str(src, "vice.s"), false).unwrap();
+        assert_eq!(
+            info.dump_vice_labels(),
+            "al C:0002 .undef\nal C:00fb .zp\nal C:8000 .start\n"
+        );
+    }
+
+    #[test]
+    fn test_map() {
+        let src = "        .org $C000
+start   lda #1
+        jmp start";
+        let info = assemble(source::from_str(src, "map.s"), false).unwrap();
+        assert_eq!(
+            info.dump_map(),
+            "START END  SIZE
+C000  C004 5
+
+TOTAL      5
+SYMBOLS    1
+"
+        );
+
+        let src = "        .org $C000
+        .byte 1,2
+        .org $C010
+        .byte 3
+        .org $B000
+        nop";
+        let info = assemble(source::from_str(src, "map.s"), false).unwrap();
+        assert_eq!(
+            info.dump_map(),
+            "START END  SIZE
+C000  C001 2
+GAP        14
+C010  C010 1
+B000  B000 1
+
+TOTAL      4
+SYMBOLS    0
+"
+        );
+
+        let src = "        .org 0
+        .ds $FFFF
+        .byte 1";
+        let info = assemble(source::from_str(src, "map.s"), false).unwrap();
+        assert_eq!(
+            info.dump_map(),
+            "START END  SIZE
+0000  FFFF 65536
+
+TOTAL      65536
+SYMBOLS    0
+"
+        );
+    }
+
     #[test]
     fn test_paren() {
         let src = ".BYTE     MIRROR+(BATTERY*2)+((MAPPER&$F)*$10)
@@ -13,15 +13,23 @@ use crate::{
 
 pub struct Macro {
     replacement_lines: Vec<Rc<Line>>,
+    /// The comment documenting the macro, as for a label.
+    comment: Option<String>,
 }
 
 impl Macro {
-    pub fn new() -> Self {
+    pub fn new(comment: Option<String>) -> Self {
         Self {
             replacement_lines: Vec::new(),
+            comment,
         }
     }
 
+    /// Return the comment documenting the macro, if any.
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_deref()
+    }
+
     /// Add another line to the replacement text.
     pub fn add_line(&mut self, line: Rc<Line>) {
         self.replacement_lines.push(line);
@@ -34,6 +42,13 @@ pub fn end_macro(text: &Line) -> bool {
     s.trim().starts_with(".endm")
 }
 
+/// Return a flag for if we're at the start of a (nested) macro definition.
+pub fn start_macro(text: &Line) -> bool {
+    let s = skip_label(text);
+    let (first, _) = split_at_first_blank(s.trim());
+    first == Some(".mac")
+}
+
 fn skip_label(l: &Line) -> String {
     let s = l.text.to_lowercase().trim().to_string();
     let (first, rest) = split_at_first_blank(&s);
@@ -80,6 +95,8 @@ pub struct MacUsage {
     mac: Rc<Macro>,
     args: Vec<String>,
     referenced_line: Rc<Line>,
+    /// The label on the invoking line, replacing `\@`.
+    label: String,
 }
 
 impl MacUsage {
@@ -88,20 +105,27 @@ impl MacUsage {
             mac,
             args,
             referenced_line,
+            label: String::new(),
         }
     }
 
     /// Insert any macro argument replacements.
     pub fn replace_args(&self, line: Rc<Line>) -> Line {
         let mut s: String = line.text.clone();
-        for (i, arg) in self.args.iter().enumerate() {
-            s = s.replace(&format!(r"\{}", i + 1), arg);
+        // Most lines have nothing to replace, so skip the searches.
+        if s.contains('\\') {
+            for (i, arg) in self.args.iter().enumerate() {
+                s = s.replace(&format!(r"\{}", i + 1), arg);
+            }
+            s = s.replace(r"\@", &self.label);
         }
-        Line::new(
-            &s,
-            &self.referenced_line.path,
+        let mut expanded = Line::with_path(
+            s,
+            self.referenced_line.path.clone(),
             self.referenced_line.line_num,
-        )
+        );
+        expanded.origin = Some(line);
+        expanded
     }
 
     /// Get a macro source.
@@ -136,8 +160,11 @@ impl Action for MacUsage {
         assembler: &mut crate::asm::Assembler,
         label: Option<Rc<crate::source::LineSlice>>,
     ) -> Result<u16, String> {
-        let _ = label;
-        assembler.src_stk.push(Box::new(self.clone().source()));
+        let mut usage = self.clone();
+        if let Some(label) = label {
+            usage.label = label.text().to_string();
+        }
+        assembler.src_stk.push(Box::new(usage.source()));
         Ok(0)
     }
 
@@ -153,19 +180,45 @@ impl Action for MacUsage {
             self.referenced_line.text.chars().count() as u16,
         ))
     }
+
+    fn is_struct_affiliated(&self) -> bool {
+        // Each line of the expansion is checked itself.
+        true
+    }
 }
 
 impl Assembler {
+    /// Parse a single macro argument, up to the next `,` outside of quotes or parentheses.
     fn parse_macro_arg(&mut self, chars: &mut BPeekable<LineChars>) -> String {
         let mut s = String::new();
-        while !self.at_eol(chars) {
-            let (c, _) = chars.peek().unwrap();
-            if *c == ',' {
-                break;
+        let mut quote: Option<char> = None;
+        let mut depth = 0u32;
+        while let Some((c, _)) = chars.peek().cloned() {
+            if let Some(q) = quote {
+                if c == q {
+                    quote = None;
+                } else if c == '\\' {
+                    // Keep an escaped quote from ending the string.
+                    s.push(c);
+                    chars.next();
+                    if let Some((c, _)) = chars.next() {
+                        s.push(c);
+                    }
+                    continue;
+                }
             } else {
-                s.push(*c);
-                chars.next().unwrap();
+                match c {
+                    '\'' | '"' => quote = Some(c),
+                    '(' => depth += 1,
+                    ')' => depth = depth.saturating_sub(1),
+                    ',' if depth == 0 => break,
+                    ';' => break,
+                    '/' if matches!(chars.peek_n(1), Some(('/', _))) => break,
+                    _ => (),
+                }
             }
+            s.push(c);
+            chars.next();
         }
         s.trim().to_string()
     }
@@ -179,11 +232,9 @@ impl Assembler {
         let mut args: Vec<String> = Vec::new();
         if !self.at_eol(chars) {
             args.push(self.parse_macro_arg(chars));
-            while !self.at_eol(chars) {
-                let (c, _) = chars.peek().unwrap();
-                if *c != ',' {
-                    break;
-                }
+            while let Some((',', _)) = chars.peek() {
+                chars.next();
+                args.push(self.parse_macro_arg(chars));
             }
         }
         Ok(Box::new(MacUsage::new(mac, args, line)))
@@ -194,6 +245,39 @@ impl Assembler {
 mod tests {
     use crate::{assemble, assemble_str, source::from_str};
 
+    #[test]
+    fn test_expansion_unchanged() {
+        // Lines with and without replacements, repeated enough to exercise the hot path.
+        let mut src = String::from(
+            r"
+        .mac put
+            lda #\1
+            sta \2
+            nop
+            .byte 'é', \1
+\@_end     .word \@_end
+        .endm
+",
+        );
+        for i in 0..50 {
+            src.push_str(&format!("l{i}     put {i}, $0400+{i}\n"));
+        }
+        let info = crate::assemble(from_str(&src, "exp.s"), true).unwrap();
+        let mut bytes = Vec::new();
+        for i in 0..50u16 {
+            let pc = i * 11;
+            bytes.extend([
+                0xA9, i as u8, 0x8D, i as u8, 0x04, 0xEA, 0xC3, 0xA9, i as u8,
+            ]);
+            bytes.extend((pc + 9).to_le_bytes());
+        }
+        assert_eq!(info.bytes, bytes);
+        assert_eq!(info.symtab["l7_end"].value, Some(7 * 11 + 9));
+        let line = &info.symtab["l7_end"].defined_at().unwrap().line();
+        assert_eq!((line.path.as_ref(), line.line_num), ("exp.s", 16));
+        assert!(info.listing.unwrap().contains("l49     put 49, $0400+49"));
+    }
+
     #[test]
     fn test_macro() {
         let msrc = r"
@@ -224,6 +308,132 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_nested_macro() {
+        let src = r"
+        .mac one
+            .byte 1
+        .endm
+        .mac outer
+            one
+            .mac inner
+                .byte 2
+                one
+            .endm
+            .byte \1
+        .endm
+        outer 3
+        inner
+        .byte 4";
+        assert_eq!(assemble_str(src, "src").unwrap(), vec![1, 3, 2, 1, 4]);
+    }
+
+    #[test]
+    fn test_macro_label() {
+        let src = r"
+        .mac inw
+            inc \1
+        .endm
+        nop
+foo     inw $02
+bar:    inw $1234
+inw     $10
+inw:    inw $20
+foobar";
+        let info = assemble(from_str(src, "src"), false).unwrap();
+        assert_eq!(
+            info.bytes,
+            vec![0xEA, 0xE6, 0x02, 0xEE, 0x34, 0x12, 0xE6, 0x10, 0xE6, 0x20]
+        );
+        assert_eq!(info.symtab["foo"].value, Some(1));
+        assert_eq!(info.symtab["bar"].value, Some(3));
+        assert_eq!(info.symtab["inw"].value, Some(8));
+        assert_eq!(info.symtab["foobar"].value, Some(10));
+    }
+
+    #[test]
+    fn test_macro_label_arg() {
+        let src = r"
+        .mac table
+\@_len     .byte \@_end-\@_len-1
+            .byte \1
+\@_end
+        .endm
+        nop
+names:  table 'abc'
+        table 'x'";
+        let info = assemble(from_str(src, "src"), false).unwrap();
+        assert_eq!(info.symtab["names"].value, Some(1));
+        assert_eq!(info.symtab["names_len"].value, Some(1));
+        assert_eq!(info.symtab["names_end"].value, Some(5));
+        assert_eq!(info.bytes, vec![0xEA, 3, b'a', b'b', b'c', 1, b'x']);
+    }
+
+    #[test]
+    fn test_macro_comment() {
+        let src = r"
+; Increment a word
+; in memory.
+        .mac inw
+            ; not the macro's comment
+            inc \1
+        .endm
+        .mac nop2   ; two nops
+            nop
+            nop
+        .endm
+
+        .mac none
+        .endm
+foo     inw $10";
+        let info = assemble(from_str(src, "src"), false).unwrap();
+        assert_eq!(info.macro_comments.len(), 2);
+        assert_eq!(info.macro_comments["inw"], "Increment a word\nin memory.\n");
+        assert_eq!(info.macro_comments["nop2"], "two nops");
+        assert!(info.symtab["foo"].comment.is_none());
+    }
+
+    #[test]
+    fn test_macro_escape() {
+        let src = r"
+        .mac line
+            .byte \1,'\n'
+        .endm
+        line 'A'";
+        assert_eq!(assemble_str(src, "src").unwrap(), vec![b'A', 10]);
+    }
+
+    #[test]
+    fn test_macro_args() {
+        let src = r"
+        .mac store3
+            lda #\1
+            sta \2
+            sta \3
+        .endm
+        store3 1, $10 , $1234
+        store3 2,$20,$30";
+        assert_eq!(
+            assemble_str(src, "src").unwrap(),
+            vec![0xA9, 1, 0x85, 0x10, 0x8D, 0x34, 0x12, 0xA9, 2, 0x85, 0x20, 0x85, 0x30]
+        );
+    }
+
+    #[test]
+    fn test_macro_arg_quotes() {
+        let src = r"
+        .mac both
+            lda \1
+            .byte \2, 0
+        .endm
+        both ($10,x), 'a, b'
+        both ($20,X) , 'c\',' ; comment, with a comma";
+        assert_eq!(
+            assemble_str(src, "src").unwrap(),
+            vec![0xA1, 0x10, b'a', b',', b' ', b'b', 0, 0xA1, 0x20, b'c', b'\'', b',', 0]
+        );
+    }
+
     #[test]
     fn test_list_macro() {
         let src = r"
@@ -261,6 +471,8 @@ mod tests {
 000012 000D EE7956             inc $5678+1
 000012 0010                .endm
 000013 0010
+
+SYMBOLS
 ";
         let is_be = assemble(from_str(src, "{src}"), true)
             .unwrap()
@@ -1,21 +1,74 @@
 use std::{error::Error, fs};
 
 use clap::Parser;
-use pop65::assemble;
+use pop65::{assemble_with_imports, AsmOptions, Cpu};
 
 fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
-    let info = assemble(pop65::from_file(&cli.source)?, cli.list_file.is_some())?;
+    let Some(cpu) = Cpu::from_name(&cli.cpu) else {
+        return Err(format!(
+            "unknown CPU '{}' (expected '6502' or '65c02'; the 65816 isn't supported)",
+            cli.cpu
+        )
+        .into());
+    };
+    let mut imports = Vec::with_capacity(cli.import_symbols.len());
+    for path in &cli.import_symbols {
+        imports.push(pop65::from_file(path)?);
+    }
+    let options = AsmOptions {
+        listing: cli.list_file.is_some(),
+        listing_disasm: cli.list_disasm,
+        listing_bytes: cli.list_bytes as usize,
+        zp_opt: !cli.no_zp_opt,
+        cpu,
+        define_files: cli.define_file,
+        rom_size: cli.rom_size.map(|size| size as usize),
+        rom_fill: cli.rom_fill,
+        ..AsmOptions::default()
+    };
+    let info = assemble_with_imports(pop65::from_file(&cli.source)?, imports, &options)?;
+    if cli.cycles {
+        eprintln!("{} cycles", info.cycles);
+    }
+    if cli.hexdump {
+        print!("{}", info.dump_hex());
+    }
     if let Some(outpath) = cli.output {
         fs::write(outpath, &info.bytes)?;
     }
     if let Some(sympath) = cli.symbol_file {
-        let symstr = info.dump_symtab();
+        let symstr = if cli.sort_by_name {
+            info.dump_symtab_by_name()
+        } else {
+            info.dump_symtab()
+        };
         fs::write(sympath, symstr)?;
     }
+    if let Some(exportpath) = cli.exports {
+        fs::write(exportpath, info.dump_exports())?;
+    }
     if let Some(dbgpath) = cli.debug_file {
         fs::write(dbgpath, &info.debug_str)?;
     }
+    for stream in &cli.debug_stream {
+        let Some((name, path)) = stream.split_once('=') else {
+            return Err(format!("expected '{{name}}={{path}}', got '{}'", stream).into());
+        };
+        fs::write(
+            path,
+            info.debug_streams.get(name).map_or("", String::as_str),
+        )?;
+    }
+    if let Some(vicepath) = cli.vice {
+        fs::write(vicepath, info.dump_vice_labels())?;
+    }
+    if let Some(mappath) = cli.map {
+        fs::write(mappath, info.dump_map())?;
+    }
+    if let Some(jsonpath) = cli.json {
+        fs::write(jsonpath, info.dump_json())?;
+    }
     if let Some(listpath) = cli.list_file {
         fs::write(listpath, info.listing.unwrap())?;
     }
@@ -33,9 +86,70 @@ struct Cli {
     #[arg(short, long)]
     symbol_file: Option<String>,
 
+    #[arg(long)]
+    sort_by_name: bool,
+
+    #[arg(long)]
+    exports: Option<String>,
+
     #[arg(short, long)]
     debug_file: Option<String>,
 
+    #[arg(long)]
+    debug_stream: Vec<String>,
+
     #[arg(short, long)]
     list_file: Option<String>,
+
+    #[arg(long, requires = "list_file")]
+    list_disasm: bool,
+
+    #[arg(long, requires = "list_file", default_value_t = 3, value_parser = clap::value_parser!(u16).range(1..))]
+    list_bytes: u16,
+
+    #[arg(short, long)]
+    import_symbols: Vec<String>,
+
+    #[arg(long)]
+    define_file: Vec<String>,
+
+    #[arg(long)]
+    no_zp_opt: bool,
+
+    #[arg(
+        long,
+        default_value = "6502",
+        help = "The CPU to assemble for: 6502 or 65c02 (the 65816 isn't supported)"
+    )]
+    cpu: String,
+
+    #[arg(short, long)]
+    map: Option<String>,
+
+    #[arg(long)]
+    vice: Option<String>,
+
+    #[arg(long)]
+    json: Option<String>,
+
+    #[arg(long)]
+    cycles: bool,
+
+    #[arg(long)]
+    hexdump: bool,
+
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..=0x10000))]
+    rom_size: Option<u32>,
+
+    #[arg(long, requires = "rom_size", default_value = "$FF", value_parser = parse_byte)]
+    rom_fill: u8,
+}
+
+/// Parse a byte given in decimal, or in hex after a `$` or `0x`.
+fn parse_byte(s: &str) -> Result<u8, String> {
+    let parsed = match s.strip_prefix('$').or_else(|| s.strip_prefix("0x")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|e| format!("bad byte '{}': {}", s, e))
 }
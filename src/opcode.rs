@@ -1,6 +1,6 @@
 //! Opcode support.
 
-use std::{collections::HashMap, fmt::Display, ops::Deref, rc::Rc, sync::LazyLock};
+use std::{cell::Cell, collections::HashMap, fmt::Display, ops::Deref, rc::Rc, sync::LazyLock};
 
 use crate::{action::Action, asm::Assembler, expr::ExprNode, source::LineSlice};
 
@@ -19,6 +19,31 @@ pub enum AMode {
     IndX,
     IndY,
     Rel,
+    /// A zero page address and a branch target, for `bbr`/`bbs`.
+    ZpRel,
+    /// The 65C02's `(zp)`, indirect through a zero page pointer without indexing.
+    ZpInd,
+    /// The 65C02's `jmp (abs,x)`.
+    AbsIndX,
+}
+
+/// A CPU variant, selecting the available instructions.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Cpu {
+    Mos6502,
+    /// The CMOS 65C02, with the Rockwell bit instructions.
+    Wdc65C02,
+}
+
+impl Cpu {
+    /// Look up a CPU by name (`6502` or `65c02`).
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "6502" => Some(Cpu::Mos6502),
+            "65c02" => Some(Cpu::Wdc65C02),
+            _ => None,
+        }
+    }
 }
 
 impl Display for AMode {
@@ -36,6 +61,9 @@ impl Display for AMode {
             AMode::IndX => "x indirect",
             AMode::IndY => "y indirect",
             AMode::Rel => "relative",
+            AMode::ZpRel => "zero page, relative",
+            AMode::ZpInd => "zero page indirect",
+            AMode::AbsIndX => "absolute x indirect",
         };
         f.write_str(name)
     }
@@ -57,6 +85,9 @@ impl AMode {
             AMode::IndX => 2,
             AMode::IndY => 2,
             AMode::Rel => 2,
+            AMode::ZpRel => 3,
+            AMode::ZpInd => 2,
+            AMode::AbsIndX => 3,
         }
     }
 }
@@ -72,6 +103,12 @@ impl Op {
             op_bytes: HashMap::from_iter(op_bytes),
         }
     }
+
+    /// Return a flag if this is a `bbr`/`bbs` instruction, taking a zero page address and a
+    /// branch target.
+    pub fn is_bit_branch(&self) -> bool {
+        self.op_bytes.contains_key(&AMode::ZpRel)
+    }
 }
 
 static OP_TABLE: LazyLock<HashMap<&'static str, Op>> = LazyLock::new(|| {
@@ -312,9 +349,207 @@ static OP_TABLE: LazyLock<HashMap<&'static str, Op>> = LazyLock::new(|| {
     ])
 });
 
-/// Lookup an opcode in the op table.
-pub fn find_op(op_name: &str) -> Option<&'static Op> {
-    OP_TABLE.deref().get(op_name)
+/// The instructions added by the 65C02: `bra`, `phx`, `phy`, `plx`, `ply`, `stz`, `trb`,
+/// and `tsb`, and the Rockwell bit instructions `rmb0`-`rmb7`, `smb0`-`smb7`, `bbr0`-`bbr7`,
+/// and `bbs0`-`bbs7`. The 6502 instructions that gained addressing modes are here too, with
+/// all of their modes: `(zp)` for `ora`, `and`, `eor`, `adc`, `sta`, `lda`, `cmp`, and `sbc`,
+/// `inc a` and `dec a`, `bit` immediate, `zp,x` and `abs,x`, and `jmp (abs,x)`.
+static CMOS_OP_TABLE: LazyLock<HashMap<String, Op>> = LazyLock::new(|| {
+    let mut table = HashMap::new();
+    let extend = |name: &str, modes: &[(AMode, u8)]| {
+        let op_bytes = OP_TABLE[name].op_bytes.iter().map(|(m, b)| (*m, *b));
+        Op::new(op_bytes.chain(modes.iter().copied()))
+    };
+    for (name, byte) in [
+        ("ora", 0x12),
+        ("and", 0x32),
+        ("eor", 0x52),
+        ("adc", 0x72),
+        ("sta", 0x92),
+        ("lda", 0xB2),
+        ("cmp", 0xD2),
+        ("sbc", 0xF2),
+    ] {
+        table.insert(name.to_string(), extend(name, &[(AMode::ZpInd, byte)]));
+    }
+    table.insert("inc".to_string(), extend("inc", &[(AMode::Imp, 0x1A)]));
+    table.insert("dec".to_string(), extend("dec", &[(AMode::Imp, 0x3A)]));
+    table.insert(
+        "bit".to_string(),
+        extend(
+            "bit",
+            &[(AMode::Imm, 0x89), (AMode::ZpX, 0x34), (AMode::AbsX, 0x3C)],
+        ),
+    );
+    table.insert("jmp".to_string(), extend("jmp", &[(AMode::AbsIndX, 0x7C)]));
+    table.insert("bra".to_string(), Op::new([(AMode::Rel, BRA)]));
+    table.insert("phx".to_string(), Op::new([(AMode::Imp, 0xDA)]));
+    table.insert("phy".to_string(), Op::new([(AMode::Imp, 0x5A)]));
+    table.insert("plx".to_string(), Op::new([(AMode::Imp, 0xFA)]));
+    table.insert("ply".to_string(), Op::new([(AMode::Imp, 0x7A)]));
+    table.insert(
+        "stz".to_string(),
+        Op::new([
+            (AMode::Zp, 0x64),
+            (AMode::ZpX, 0x74),
+            (AMode::Abs, 0x9C),
+            (AMode::AbsX, 0x9E),
+        ]),
+    );
+    table.insert(
+        "trb".to_string(),
+        Op::new([(AMode::Zp, 0x14), (AMode::Abs, 0x1C)]),
+    );
+    table.insert(
+        "tsb".to_string(),
+        Op::new([(AMode::Zp, 0x04), (AMode::Abs, 0x0C)]),
+    );
+    for bit in 0..8u8 {
+        let row = bit << 4;
+        table.insert(format!("rmb{bit}"), Op::new([(AMode::Zp, row | 0x07)]));
+        table.insert(format!("smb{bit}"), Op::new([(AMode::Zp, row | 0x87)]));
+        table.insert(format!("bbr{bit}"), Op::new([(AMode::ZpRel, row | 0x0F)]));
+        table.insert(format!("bbs{bit}"), Op::new([(AMode::ZpRel, row | 0x8F)]));
+    }
+    table
+});
+
+/// Every opcode byte in the op tables, with its instruction's name and addressing mode.
+static DISASM_TABLE: LazyLock<HashMap<u8, (&'static str, AMode)>> = LazyLock::new(|| {
+    let ops = OP_TABLE
+        .iter()
+        .map(|(name, op)| (*name, op))
+        .chain(CMOS_OP_TABLE.iter().map(|(name, op)| (name.as_str(), op)));
+    let mut table = HashMap::new();
+    for (name, op) in ops {
+        for (amode, byte) in &op.op_bytes {
+            table.insert(*byte, (name, *amode));
+        }
+    }
+    table
+});
+
+/// Decode the instructions in `bytes`, loaded at `pc`, back into source form: the mnemonic
+/// in upper case and the operand in hexadecimal, each instruction separated by ` : `.
+///
+/// Returns `None` if the bytes aren't a whole number of instructions.
+pub fn disassemble(bytes: &[u8], mut pc: u16) -> Option<String> {
+    let mut instructions = Vec::new();
+    let mut rest = bytes;
+    while let Some(byte) = rest.first() {
+        let (name, amode) = DISASM_TABLE.get(byte)?;
+        let size = amode.byte_size() as usize;
+        let operand = rest.get(1..size)?;
+        let word = || u16::from_le_bytes([operand[0], operand[1]]);
+        // Branch offsets are relative to the end of the instruction.
+        let target = |offset: u8| {
+            pc.wrapping_add(size as u16)
+                .wrapping_add(offset as i8 as u16)
+        };
+        let operand = match amode {
+            AMode::Imp => String::new(),
+            AMode::Imm => format!(" #${:02X}", operand[0]),
+            AMode::Zp => format!(" ${:02X}", operand[0]),
+            AMode::ZpX => format!(" ${:02X},X", operand[0]),
+            AMode::ZpY => format!(" ${:02X},Y", operand[0]),
+            AMode::Abs => format!(" ${:04X}", word()),
+            AMode::AbsX => format!(" ${:04X},X", word()),
+            AMode::AbsY => format!(" ${:04X},Y", word()),
+            AMode::Ind => format!(" (${:04X})", word()),
+            AMode::IndX => format!(" (${:02X},X)", operand[0]),
+            AMode::IndY => format!(" (${:02X}),Y", operand[0]),
+            AMode::Rel => format!(" ${:04X}", target(operand[0])),
+            AMode::ZpRel => format!(" ${:02X},${:04X}", operand[0], target(operand[1])),
+            AMode::ZpInd => format!(" (${:02X})", operand[0]),
+            AMode::AbsIndX => format!(" (${:04X},X)", word()),
+        };
+        instructions.push(format!("{}{}", name.to_ascii_uppercase(), operand));
+        pc = pc.wrapping_add(size as u16);
+        rest = &rest[size..];
+    }
+    Some(instructions.join(" : "))
+}
+
+/// The base cycle count of each instruction, by name and addressing mode.
+///
+/// This leaves out the extra cycle for a taken branch (but `bra` is always taken), and for a
+/// branch or an indexed read crossing a page boundary.
+static CYCLE_TABLE: LazyLock<HashMap<(&'static str, AMode), u8>> = LazyLock::new(|| {
+    let mut table = HashMap::new();
+    for (name, amode) in DISASM_TABLE.values() {
+        let read_modify_write = matches!(*name, "asl" | "lsr" | "rol" | "ror" | "inc" | "dec")
+            || name.starts_with("rmb")
+            || name.starts_with("smb")
+            || matches!(*name, "trb" | "tsb");
+        let store = matches!(*name, "sta" | "stx" | "sty" | "stz");
+        let cycles = match (*name, amode) {
+            ("brk", _) => 7,
+            ("rti" | "rts" | "jsr", _) => 6,
+            ("jmp", AMode::Abs) => 3,
+            ("jmp", AMode::AbsIndX) => 6,
+            ("jmp", _) => 5,
+            ("pha" | "php" | "phx" | "phy" | "bra", _) => 3,
+            ("pla" | "plp" | "plx" | "ply", _) => 4,
+            (_, AMode::Imp | AMode::Imm | AMode::Rel) => 2,
+            (_, AMode::ZpRel) => 5,
+            (_, AMode::Zp) if read_modify_write => 5,
+            (_, AMode::ZpX | AMode::Abs) if read_modify_write => 6,
+            (_, AMode::AbsX) if read_modify_write => 7,
+            (_, AMode::AbsX | AMode::AbsY) if store => 5,
+            (_, AMode::IndY) if store => 6,
+            (_, AMode::Zp) => 3,
+            (_, AMode::ZpX | AMode::ZpY | AMode::Abs | AMode::AbsX | AMode::AbsY) => 4,
+            (_, AMode::IndX) => 6,
+            (_, AMode::IndY | AMode::Ind | AMode::ZpInd) => 5,
+            (_, AMode::AbsIndX) => 6,
+        };
+        table.insert((*name, *amode), cycles);
+    }
+    table
+});
+
+/// Return the total base cycle count of the instructions in `bytes`, as given by
+/// `CYCLE_TABLE`.
+///
+/// Returns `None` if the bytes aren't a whole number of instructions.
+pub fn cycles(bytes: &[u8]) -> Option<u32> {
+    let mut total = 0;
+    let mut rest = bytes;
+    while let Some(byte) = rest.first() {
+        let (name, amode) = DISASM_TABLE.get(byte)?;
+        total += CYCLE_TABLE[&(*name, *amode)] as u32;
+        rest = rest.get(amode.byte_size() as usize..)?;
+    }
+    Some(total)
+}
+
+/// Synonyms for instructions used by other assemblers, and the instruction each stands
+/// for, available under `.aliases`.
+const OP_ALIASES: [(&str, &str); 6] = [
+    ("blt", "bcc"),
+    ("blo", "bcc"),
+    ("bge", "bcs"),
+    ("bhs", "bcs"),
+    ("bze", "beq"),
+    ("bnz", "bne"),
+];
+
+/// Lookup an opcode in the op table for the CPU, then (if `aliases` is set) among the
+/// synonyms in `OP_ALIASES`.
+///
+/// The 65C02's table comes first, as it extends some 6502 instructions.
+pub fn find_op(op_name: &str, cpu: Cpu, aliases: bool) -> Option<&'static Op> {
+    match cpu {
+        Cpu::Mos6502 => None,
+        Cpu::Wdc65C02 => CMOS_OP_TABLE.get(op_name),
+    }
+    .or_else(|| OP_TABLE.deref().get(op_name))
+    .or_else(|| {
+        let (_, op_name) = OP_ALIASES
+            .iter()
+            .find(|(alias, _)| aliases && *alias == op_name)?;
+        OP_TABLE.get(op_name)
+    })
 }
 
 /// A 6502 opcode in the actual source code.
@@ -323,8 +558,25 @@ pub struct OpCode {
     op_slice: Rc<LineSlice>,
     amode: AMode,
     expr: Option<Box<ExprNode>>,
+    /// The branch target of a `bbr`/`bbs`, whose `expr` is the zero page address.
+    target: Option<Box<ExprNode>>,
+    /// Set in pass1 if this branch is assembled as an inverse branch around a `jmp`, or, for
+    /// `bra`, as just the `jmp`.
+    long_branch: Cell<bool>,
 }
 
+/// The size of a branch rewritten to an inverse branch around a `jmp`.
+const LONG_BRANCH_SIZE: u16 = 5;
+
+/// Flipping this bit of a branch opcode gives the branch on the opposite condition.
+const INVERSE_BRANCH_BIT: u8 = 0x20;
+
+/// The opcode for an absolute `jmp`.
+const JMP_ABS: u8 = 0x4C;
+
+/// The opcode for the 65C02's `bra`, which always branches, so it has no inverse.
+const BRA: u8 = 0x80;
+
 impl OpCode {
     pub fn new(
         op: &'static Op,
@@ -337,15 +589,39 @@ impl OpCode {
             op_slice,
             amode,
             expr,
+            target: None,
+            long_branch: Cell::new(false),
         }
     }
 
+    /// Construct a `bbr`/`bbs` instruction, testing a bit at the zero page address.
+    pub fn new_bit_branch(
+        op: &'static Op,
+        op_slice: Rc<LineSlice>,
+        zp: Box<ExprNode>,
+        target: Box<ExprNode>,
+    ) -> Self {
+        let mut opcode = Self::new(op, op_slice, AMode::ZpRel, Some(zp));
+        opcode.target = Some(target);
+        opcode
+    }
+
+    /// Return the offset from the end of a branch at the current PC to the target.
+    fn branch_offset(&self, asm: &mut Assembler) -> Result<i32, String> {
+        let (here, target) = match &self.target {
+            Some(target) => ((asm.pc() as i32) + 3, target),
+            None => ((asm.pc() as i32) + 2, self.expr.as_ref().unwrap()),
+        };
+        let there = target.eval_word(asm)? as i32;
+        Ok(there - here)
+    }
+
     /// Return a flag if this thing has an expression that can be evaluated
     /// into zero page.
     fn is_zp(&self, asm: &mut Assembler) -> bool {
         if let Some(expr) = self.expr.as_ref() {
             if let Ok(val) = expr.eval(asm) {
-                if val <= (u8::MAX as u16) {
+                if val <= (u8::MAX as u32) {
                     return true;
                 }
             }
@@ -353,6 +629,14 @@ impl OpCode {
         false
     }
 
+    /// The size of this branch when it's too far for a relative branch.
+    fn long_branch_size(&self, amode: AMode) -> u16 {
+        match self.op.op_bytes[&amode] {
+            BRA => AMode::Abs.byte_size() as u16,
+            _ => LONG_BRANCH_SIZE,
+        }
+    }
+
     /// Construct the *real* addressing mode, taking into account zero-page, etc.
     fn real_amode(&self, asm: &mut Assembler) -> AMode {
         match self.amode {
@@ -364,30 +648,41 @@ impl OpCode {
             AMode::Abs => {
                 if self.op.op_bytes.contains_key(&AMode::Rel) {
                     AMode::Rel
-                } else if self.op.op_bytes.contains_key(&AMode::Zp) && self.is_zp(asm) {
+                } else if self.op.op_bytes.contains_key(&AMode::Zp)
+                    && (!self.op.op_bytes.contains_key(&AMode::Abs)
+                        || asm.zp_opt && self.is_zp(asm))
+                {
+                    // Zero page only instructions (`rmb`/`smb`) are always zero page.
                     AMode::Zp
                 } else {
                     AMode::Abs
                 }
             }
             AMode::AbsX => {
-                if self.op.op_bytes.contains_key(&AMode::ZpX) && self.is_zp(asm) {
+                if self.op.op_bytes.contains_key(&AMode::ZpX) && asm.zp_opt && self.is_zp(asm) {
                     AMode::ZpX
                 } else {
                     AMode::AbsX
                 }
             }
             AMode::AbsY => {
-                if self.op.op_bytes.contains_key(&AMode::ZpY) && self.is_zp(asm) {
+                if self.op.op_bytes.contains_key(&AMode::ZpY) && asm.zp_opt && self.is_zp(asm) {
                     AMode::ZpY
                 } else {
                     AMode::AbsY
                 }
             }
+            // `(...)` is `(zp)` for every instruction but `jmp`, and `(...,x)` is `(abs,x)`
+            // for `jmp`.
+            AMode::Ind if self.op.op_bytes.contains_key(&AMode::ZpInd) => AMode::ZpInd,
             AMode::Ind => AMode::Ind,
+            AMode::IndX if self.op.op_bytes.contains_key(&AMode::AbsIndX) => AMode::AbsIndX,
             AMode::IndX => AMode::IndX,
             AMode::IndY => AMode::IndY,
             AMode::Rel => AMode::Rel,
+            AMode::ZpRel => AMode::ZpRel,
+            AMode::ZpInd => AMode::ZpInd,
+            AMode::AbsIndX => AMode::AbsIndX,
         }
     }
 
@@ -395,40 +690,70 @@ impl OpCode {
     /// the given amode.
     fn eval(&self, amode: AMode, asm: &mut Assembler) -> Result<Vec<u8>, String> {
         if let Some(expr) = self.expr.as_ref() {
-            let val = expr.eval(asm)?;
-            let mut val_bytes = Vec::from(val.to_le_bytes());
-            if amode == AMode::Rel {
-                let here = (asm.pc as i32) + 2;
-                let there = val as i32;
-                let offset = there - here;
+            if amode == AMode::Rel || amode == AMode::ZpRel {
+                let mut bytes = Vec::with_capacity(2);
+                if amode == AMode::ZpRel {
+                    bytes.push(expr.eval_byte(asm)?);
+                }
+                let offset = self.branch_offset(asm)?;
                 if let Ok(byte_offset) = i8::try_from(offset) {
-                    return Ok(Vec::from(byte_offset.to_le_bytes()));
+                    bytes.push(byte_offset as u8);
+                    Ok(bytes)
                 } else {
-                    return self
-                        .line_slice()
-                        .err(&format!("offset {offset} out of range"));
+                    let direction = if offset < 0 { "back" } else { "ahead" };
+                    let fix = if amode == AMode::Rel {
+                        "use an inverse branch + jmp, or '.autobranch on'"
+                    } else {
+                        "use an inverse branch + jmp"
+                    };
+                    self.line_slice().err(&format!(
+                        "'{}' target is {} bytes {} (branches reach 128 back or 127 ahead); {}",
+                        self.op_slice.text(),
+                        offset.abs(),
+                        direction,
+                        fix
+                    ))
                 }
             } else {
                 match amode.byte_size() - 1 {
-                    0 => panic!(),
-                    1 => {
-                        if val_bytes.pop() != Some(0) {
-                            return self
-                                .line_slice()
-                                .err(&format!("value {val:04X} out of range"));
-                        }
-                    }
-                    2 => (),
+                    1 => Ok(vec![expr.eval_byte(asm)?]),
+                    2 => Ok(Vec::from(expr.eval_word(asm)?.to_le_bytes())),
                     _ => panic!(),
                 }
             }
-            Ok(val_bytes)
         } else {
             Ok(Vec::new())
         }
     }
 }
 
+impl OpCode {
+    /// Return an error for an addressing mode the instruction doesn't support.
+    fn amode_err<T>(&self, amode: AMode) -> Result<T, String> {
+        let modes = &self.op.op_bytes;
+        let name = self.op_slice.text();
+        if let (Some(expr), true) = (
+            &self.expr,
+            modes.len() == 1 && modes.contains_key(&AMode::Imp),
+        ) {
+            return expr.slice.err(&format!("'{}' takes no operand", name));
+        }
+        if amode == AMode::Imp {
+            return self
+                .line_slice()
+                .err(&format!("'{}' needs an operand", name));
+        }
+        let mut supported = Vec::from_iter(modes.keys().map(|m| format!("'{}'", m)));
+        supported.sort();
+        self.line_slice().err(&format!(
+            "addressing mode '{}' not supported for '{}' (it supports {})",
+            amode,
+            name,
+            supported.join(", ")
+        ))
+    }
+}
+
 impl Action for OpCode {
     fn pass1(
         &self,
@@ -438,28 +763,347 @@ impl Action for OpCode {
         let _ = label;
         let amode = self.real_amode(assembler);
         if !self.op.op_bytes.contains_key(&amode) {
-            self.line_slice().err(&format!(
-                "addressing mode '{}' not supported for '{}'",
-                amode,
-                self.op_slice.text()
-            ))
+            self.amode_err(amode)
         } else {
+            if amode == AMode::Rel && assembler.long_branches {
+                // The same size whatever the distance, so forward references are fine.
+                self.long_branch.set(true);
+                return Ok(self.long_branch_size(amode));
+            }
+            if amode == AMode::Rel && assembler.auto_branch {
+                // Only targets already known in pass1 can be checked.
+                if let Ok(offset) = self.branch_offset(assembler) {
+                    if i8::try_from(offset).is_err() {
+                        self.long_branch.set(true);
+                        return Ok(self.long_branch_size(amode));
+                    }
+                }
+            }
             Ok(amode.byte_size() as u16)
         }
     }
 
     fn pass2(&self, assembler: &mut crate::asm::Assembler) -> Result<Vec<u8>, String> {
         let amode = self.real_amode(assembler);
+        if self.long_branch.get() {
+            let target = self.expr.as_ref().unwrap().eval_word(assembler)?;
+            let mut bytes = match self.op.op_bytes[&amode] {
+                BRA => vec![JMP_ABS],
+                // The inverse branch skips over the 3-byte jmp.
+                op => vec![op ^ INVERSE_BRANCH_BIT, 3, JMP_ABS],
+            };
+            bytes.extend(target.to_le_bytes());
+            return Ok(bytes);
+        }
         let mut bytes = self.eval(amode, assembler)?;
+        if amode == AMode::Ind
+            && assembler.warn_jmp_bug
+            && assembler.cpu == Cpu::Mos6502
+            && bytes[0] == 0xFF
+        {
+            // The NMOS 6502 doesn't carry into the high byte of the pointer's address.
+            let ptr = u16::from_le_bytes([bytes[0], bytes[1]]);
+            assembler.warn(
+                &self.line_slice(),
+                &format!(
+                    "jmp (${:04X}) reads its high byte from ${:04X}, not ${:04X}, on the 6502",
+                    ptr,
+                    ptr & 0xFF00,
+                    ptr.wrapping_add(1)
+                ),
+            );
+        }
         bytes.insert(0, self.op.op_bytes[&amode]);
         Ok(bytes)
     }
 
     fn line_slice(&self) -> Rc<LineSlice> {
-        if let Some(expr) = self.expr.as_ref() {
+        if let Some(target) = self.target.as_ref() {
+            Rc::new(self.op_slice.join(&target.slice))
+        } else if let Some(expr) = self.expr.as_ref() {
             Rc::new(self.op_slice.join(&expr.slice))
         } else {
             self.op_slice.clone()
         }
     }
+
+    fn is_opcode(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assemble, assemble_str, assemble_with_diagnostics, source};
+
+    use super::{cycles, disassemble};
+
+    #[test]
+    fn test_disassemble() {
+        let src = "
+        .org $1000
+        lda $1234
+        sta ($10),y
+loop    bne loop
+        jmp ($FFFC)
+        .cpu '65c02'
+        bbs7 $02, loop
+        nop";
+        let bytes = assemble_str(src, "dis.s").unwrap();
+        assert_eq!(
+            disassemble(&bytes, 0x1000).unwrap(),
+            "LDA $1234 : STA ($10),Y : BNE $1005 : JMP ($FFFC) : BBS7 $02,$1005 : NOP"
+        );
+        assert_eq!(disassemble(&[0xAD, 0x34], 0), None);
+        assert_eq!(disassemble(&[0x02], 0), None);
+    }
+
+    #[test]
+    fn test_aliases() {
+        let aliased = "        .aliases on
+target  blt target
+        bge target
+        bnz target
+        .aliases off
+blt     .byte 1";
+        let canonical = "target  bcc target
+        bcs target
+        bne target
+blt     .byte 1";
+        assert_eq!(
+            assemble_str(aliased, "alias.s").unwrap(),
+            assemble_str(canonical, "alias.s").unwrap()
+        );
+        // Off by default, so the names are free for labels.
+        assert!(assemble_str("target  blt target", "alias.s").is_err());
+    }
+
+    #[test]
+    fn test_cycles() {
+        let src = "
+        .org $1000
+        .cycles copy, done
+copy    ldx #8
+loop    lda $2000,x
+        sta $10,x
+        dex
+        bne loop
+done    rts
+        inc $1234,x
+        sta ($10),y
+        jmp ($fffc)";
+        let info = assemble(source::from_str(src, "cycles.s"), false).unwrap();
+        assert_eq!(
+            info.cycle_reports,
+            vec!["cycles.s:3:9: 1000-100A takes 14 cycles".to_string()]
+        );
+        assert_eq!(info.cycles, 14 + 6 + 7 + 6 + 5);
+        assert_eq!(cycles(&[0x20, 0x00, 0x10, 0xEA]), Some(8));
+        assert_eq!(cycles(&[0x20, 0x00]), None);
+    }
+
+    #[test]
+    fn test_long_branch() {
+        let src = "
+        .org $1000
+        .longbranch on
+        beq far
+        bne near
+near    bcc * - 1000
+        .longbranch off
+        beq near
+        .org $2000
+far     rts";
+        let bytes = assemble_str(src, "long.s").unwrap();
+        assert_eq!(
+            bytes,
+            vec![
+                0xD0, 3, 0x4C, 0x00, 0x20, // beq far
+                0xF0, 3, 0x4C, 0x0A, 0x10, // bne near
+                0xB0, 3, 0x4C, 0x22, 0x0C, // bcc * - 1000
+                0xF0, 0xF9, // beq near
+                0x60,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_warn_jmp_bug() {
+        let src = "
+        jmp ($10FF)
+        .warn-jmpbug on
+        jmp ($10FF)
+        jmp ($1000)
+        jmp ($20FE)
+        .cpu '65c02'
+        jmp ($10FF)
+        .cpu '6502'
+        .warnjmpbug off
+        jmp ($10FF)";
+        let info = assemble(source::from_str(src, "jmp.s"), false).unwrap();
+        assert_eq!(
+            info.warnings,
+            vec![
+                "jmp.s:4:9: warning: jmp ($10FF) reads its high byte from $1000, not $1100, \
+                  on the 6502"
+            ]
+        );
+        assert_eq!(info.bytes[3..6], [0x6C, 0xFF, 0x10]);
+        let info = assemble_with_diagnostics(source::from_str(".warn-jmpbugs on", "jmp.s"), false);
+        assert!(info.diagnostics[0]
+            .message
+            .starts_with("jmp.s:1:1: unknown warning '.warn-jmpbugs' (expected '.warn-jmpbug')"));
+        let info = assemble_with_diagnostics(source::from_str(".warn- on", "jmp.s"), false);
+        assert!(info.diagnostics[0]
+            .message
+            .starts_with("jmp.s:1:1: expected a warning name after '.warn-'"));
+    }
+
+    #[test]
+    fn test_bit_instructions() {
+        let src = "
+        .cpu \"65c02\"
+        smb3 $02
+target  rmb0 flag
+        bbr1 $02, target
+        bbs7 flag,later
+        nop
+later   .cpu '6502'
+smb3    nop
+flag    = $10";
+        assert_eq!(
+            assemble_str(src, "bit.s"),
+            Ok(vec![
+                0xB7, 0x02, 0x07, 0x10, 0x1F, 0x02, 0xFB, 0xFF, 0x10, 0x01, 0xEA, 0xEA
+            ])
+        );
+        assert!(assemble_str("smb3 $02", "bit.s").is_err());
+        assert!(assemble_str(".cpu '65c02'\nbbr0 $02", "bit.s").is_err());
+        assert!(assemble_str(".cpu '65c02'\nsmb0 $1234", "bit.s").is_err());
+        assert!(assemble_str(".cpu '65816'", "bit.s").is_err());
+    }
+
+    #[test]
+    fn test_cmos_instructions() {
+        let src = "
+        .org $1000
+        .cpu '65c02'
+loop    bra loop
+        lda ($10)
+        sta [$20]
+        inc a
+        dec a
+        bit #$80
+        bit $10,x
+        bit $1234,x
+        jmp ($1234,x)
+        lda ($10,x)
+        jmp ($1234)";
+        let bytes = assemble_str(src, "cmos.s").unwrap();
+        assert_eq!(
+            bytes,
+            vec![
+                0x80, 0xFE, 0xB2, 0x10, 0x92, 0x20, 0x1A, 0x3A, 0x89, 0x80, 0x34, 0x10, 0x3C, 0x34,
+                0x12, 0x7C, 0x34, 0x12, 0xA1, 0x10, 0x6C, 0x34, 0x12
+            ]
+        );
+        assert_eq!(
+            disassemble(&bytes[..6], 0x1000).unwrap(),
+            "BRA $1000 : LDA ($10) : STA ($20)"
+        );
+        assert_eq!(
+            disassemble(&[0x7C, 0x34, 0x12], 0).unwrap(),
+            "JMP ($1234,X)"
+        );
+        assert_eq!(
+            cycles(&[0x80, 0xFE, 0xB2, 0x10, 0x7C, 0x34, 0x12]),
+            Some(14)
+        );
+        // The 6502 has none of them.
+        for line in ["bra *", "lda ($10)", "inc a", "bit #1", "jmp ($1234,x)"] {
+            assert!(assemble_str(line, "cmos.s").is_err(), "{line}");
+        }
+        assert!(assemble_str(".cpu '65c02'\nlda ($1234)", "cmos.s").is_err());
+
+        // With no inverse branch, a long `bra` is just a `jmp`.
+        let src = "
+        .org $1000
+        .cpu '65c02'
+        .longbranch on
+        bra $2000
+        beq $2000";
+        assert_eq!(
+            assemble_str(src, "cmos.s"),
+            Ok(vec![0x4C, 0x00, 0x20, 0xD0, 3, 0x4C, 0x00, 0x20])
+        );
+    }
+
+    #[test]
+    fn test_amode_errors() {
+        let error = |src: &str| {
+            let info = assemble_with_diagnostics(source::from_str(src, "amode.s"), false);
+            info.diagnostics[0]
+                .message
+                .lines()
+                .next()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(error("inx 5"), "amode.s:1:5: 'inx' takes no operand");
+        assert_eq!(
+            error("jmp $1234,x"),
+            "amode.s:1:1: addressing mode 'absolute, x indexed' not supported for 'jmp' \
+            (it supports 'absolute', 'indirect')"
+        );
+        assert_eq!(error("lda"), "amode.s:1:1: 'lda' needs an operand");
+        assert_eq!(assemble_str("inx", "amode.s"), Ok(vec![0xE8]));
+    }
+
+    #[test]
+    fn test_branch_range() {
+        let src = "
+far     nop
+        .ds 200
+        beq far";
+        let err = assemble(source::from_str(src, "src"), false).err().unwrap();
+        assert_eq!(err, "1 errors in pass 2");
+        let info = assemble_with_diagnostics(source::from_str(src, "src"), false);
+        assert!(info.diagnostics[0].message.starts_with(
+            "src:4:9: 'beq' target is 203 bytes back (branches reach 128 back or 127 ahead); \
+             use an inverse branch + jmp, or '.autobranch on'\n"
+        ));
+        let info = assemble_with_diagnostics(source::from_str("beq *+130", "src"), false);
+        assert!(info.diagnostics[0]
+            .message
+            .contains("'beq' target is 128 bytes ahead"));
+        assert!(assemble_str("beq *+130", "src").is_err());
+        assert_eq!(assemble_str("beq *+129", "src"), Ok(vec![0xF0, 0x7F]));
+        assert_eq!(
+            assemble_str(".org $100\nbeq *-126", "src"),
+            Ok(vec![0xF0, 0x80])
+        );
+        assert!(assemble_str(".org $100\nbeq *-127", "src").is_err());
+    }
+
+    #[test]
+    fn test_autobranch() {
+        let src = "
+        .autobranch on
+far     nop
+        .ds 200
+        beq far
+        bcs far
+near    bne near
+        .autobranch off
+        bne near";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(
+            &info.bytes[201..],
+            &[0xD0, 3, 0x4C, 0, 0, 0x90, 3, 0x4C, 0, 0, 0xD0, 0xFE, 0xD0, 0xFC]
+        );
+        assert_eq!(info.symtab["near"].value, Some(211));
+
+        // Forward branches can't be rewritten.
+        assert!(assemble_str(".autobranch on\nbeq far\n.ds 200\nfar", "src").is_err());
+    }
 }
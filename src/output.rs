@@ -0,0 +1,272 @@
+//! Renderings of the assembly results beyond the binary: JSON for other tools, and a hex dump.
+
+use std::fmt::Write;
+
+use crate::{source::LineSlice, AsmInfo};
+
+/// Serialize the symbol table and segment layout to JSON.
+///
+/// Symbols are sorted by name, each with its value (as a number and as hex, or null if it
+/// was never defined), where it was defined, and every reference, sorted by position.
+/// Segments are in output order, each with its start and end address and size.
+pub fn json(info: &AsmInfo) -> String {
+    let mut symbols = Vec::from_iter(info.symtab.values());
+    symbols.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut s = "{\n  \"symbols\": [".to_string();
+    for (i, symbol) in symbols.into_iter().enumerate() {
+        s.push_str(if i == 0 { "\n" } else { ",\n" });
+        s.push_str(&format!("    {{\"name\": {}, ", string(&symbol.name)));
+        match symbol.value {
+            Some(v) => s.push_str(&format!("\"value\": {v}, \"hex\": \"{v:04X}\", ")),
+            None => s.push_str("\"value\": null, \"hex\": null, "),
+        }
+        s.push_str("\"defined_at\": ");
+        match &symbol.defined_at {
+            Some(slice) => s.push_str(&position(slice)),
+            None => s.push_str("null"),
+        }
+        let mut refs = Vec::from_iter(&symbol.references);
+        refs.sort_by_key(|r| (r.path(), r.line_num(), r.start_char));
+        s.push_str(", \"references\": [");
+        for (j, r) in refs.into_iter().enumerate() {
+            if j > 0 {
+                s.push_str(", ");
+            }
+            s.push_str(&position(r));
+        }
+        s.push_str("], \"comment\": ");
+        match &symbol.comment {
+            Some(c) => s.push_str(&string(c.trim_end())),
+            None => s.push_str("null"),
+        }
+        s.push_str(&format!(", \"exported\": {}}}", symbol.exported));
+    }
+    s.push_str("\n  ],\n  \"segments\": [");
+    for (i, (start, bytes)) in info.segments.iter().enumerate() {
+        s.push_str(if i == 0 { "\n" } else { ",\n" });
+        let end = start.wrapping_add((bytes.len() - 1) as u16);
+        s.push_str(&format!(
+            "    {{\"start\": {start}, \"end\": {end}, \"size\": {}}}",
+            bytes.len()
+        ));
+    }
+    s.push_str("\n  ]\n}\n");
+    s
+}
+
+/// The number of bytes on each line of a hex dump.
+const HEXDUMP_WIDTH: usize = 16;
+
+/// Render segments as a hex dump, like `xxd`: each line has its address, up to 16 bytes in
+/// hex, and the same bytes as ASCII, with a `.` for any that isn't printable.
+pub fn hexdump(segments: &[(u16, Vec<u8>)]) -> String {
+    let mut s = String::new();
+    for (start, bytes) in segments {
+        for (i, chunk) in bytes.chunks(HEXDUMP_WIDTH).enumerate() {
+            let addr = start.wrapping_add((i * HEXDUMP_WIDTH) as u16);
+            let hex = Vec::from_iter(chunk.iter().map(|b| format!("{b:02X}"))).join(" ");
+            let ascii = String::from_iter(chunk.iter().map(|b| match b {
+                0x20..=0x7E => *b as char,
+                _ => '.',
+            }));
+            writeln!(s, "{addr:04X}: {hex:0$}  {ascii}", HEXDUMP_WIDTH * 3 - 1).unwrap();
+        }
+    }
+    s
+}
+
+/// Return a slice's position as a JSON object of its path, line number, and column.
+fn position(slice: &LineSlice) -> String {
+    format!(
+        "{{\"path\": {}, \"line\": {}, \"column\": {}}}",
+        string(slice.path()),
+        slice.line_num(),
+        slice.start_char + 1
+    )
+}
+
+/// Return a string as a quoted, escaped JSON string.
+fn string(text: &str) -> String {
+    let mut s = String::with_capacity(text.len() + 2);
+    s.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => s.push_str("\\\""),
+            '\\' => s.push_str("\\\\"),
+            '\n' => s.push_str("\\n"),
+            '\r' => s.push_str("\\r"),
+            '\t' => s.push_str("\\t"),
+            c if c.is_control() => write!(s, "\\u{:04x}", c as u32).unwrap(),
+            c => s.push(c),
+        }
+    }
+    s.push('"');
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, iter::Peekable, str::Chars};
+
+    use crate::{assemble, source};
+
+    use super::hexdump;
+
+    /// A parsed JSON value, just enough to check the output.
+    #[derive(Debug, PartialEq)]
+    enum Json {
+        Null,
+        Bool(bool),
+        Num(f64),
+        Str(String),
+        Arr(Vec<Json>),
+        Obj(HashMap<String, Json>),
+    }
+
+    impl Json {
+        fn get(&self, key: &str) -> &Json {
+            match self {
+                Json::Obj(fields) => &fields[key],
+                _ => panic!("not an object"),
+            }
+        }
+
+        fn items(&self) -> &[Json] {
+            match self {
+                Json::Arr(items) => items,
+                _ => panic!("not an array"),
+            }
+        }
+    }
+
+    fn parse(chars: &mut Peekable<Chars>) -> Json {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let value = match chars.next().unwrap() {
+            'n' => {
+                chars.nth(2);
+                Json::Null
+            }
+            't' => {
+                chars.nth(2);
+                Json::Bool(true)
+            }
+            'f' => {
+                chars.nth(3);
+                Json::Bool(false)
+            }
+            '"' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next().unwrap() {
+                        '"' => break,
+                        '\\' => match chars.next().unwrap() {
+                            'n' => s.push('\n'),
+                            't' => s.push('\t'),
+                            'r' => s.push('\r'),
+                            'u' => {
+                                let hex = String::from_iter(chars.take(4));
+                                s.push(
+                                    char::from_u32(u32::from_str_radix(&hex, 16).unwrap()).unwrap(),
+                                );
+                            }
+                            c => s.push(c),
+                        },
+                        c => s.push(c),
+                    }
+                }
+                Json::Str(s)
+            }
+            '[' => {
+                let mut items = Vec::new();
+                loop {
+                    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                    if chars.next_if_eq(&']').is_some() {
+                        break;
+                    }
+                    items.push(parse(chars));
+                    while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+                }
+                Json::Arr(items)
+            }
+            '{' => {
+                let mut fields = HashMap::new();
+                loop {
+                    while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+                    if chars.next_if_eq(&'}').is_some() {
+                        break;
+                    }
+                    let Json::Str(key) = parse(chars) else {
+                        panic!("expected key")
+                    };
+                    while chars.next_if(|c| c.is_whitespace() || *c == ':').is_some() {}
+                    fields.insert(key, parse(chars));
+                }
+                Json::Obj(fields)
+            }
+            c => {
+                let mut s = c.to_string();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_digit() || *c == '.') {
+                    s.push(c);
+                }
+                Json::Num(s.parse().unwrap())
+            }
+        };
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        value
+    }
+
+    #[test]
+    fn test_hexdump() {
+        let bytes = Vec::from_iter(b"Hello, 6502!\x00\x01\xFF~".iter().copied());
+        let dump = hexdump(&[(0x8000, bytes), (0x9000, vec![0xA9, 0x41])]);
+        let lines = Vec::from_iter(dump.lines());
+        assert_eq!(
+            lines[0],
+            "8000: 48 65 6C 6C 6F 2C 20 36 35 30 32 21 00 01 FF 7E  Hello, 6502!...~"
+        );
+        assert_eq!(
+            lines[1],
+            "9000: A9 41                                            .A"
+        );
+        assert_eq!(lines.len(), 2);
+    }
+
+    #[test]
+    fn test_json() {
+        let src = "\t.org $c000\n\t; The \"start\"\nstart\tlda #0\n\tjmp start\n\t.org $d000\n\t.byte 1\n";
+        let info = assemble(source::from_str(src, "json.s"), false).unwrap();
+        let json = parse(&mut info.dump_json().chars().peekable());
+        let symbols = json.get("symbols").items();
+        let start = symbols
+            .iter()
+            .find(|s| *s.get("name") == Json::Str("start".to_string()))
+            .unwrap();
+        assert_eq!(*start.get("value"), Json::Num(49152.0));
+        assert_eq!(*start.get("hex"), Json::Str("C000".to_string()));
+        assert_eq!(
+            *start.get("comment"),
+            Json::Str("The \"start\"".to_string())
+        );
+        let at = start.get("defined_at");
+        assert_eq!(*at.get("path"), Json::Str("json.s".to_string()));
+        assert_eq!(*at.get("line"), Json::Num(3.0));
+        assert_eq!(*at.get("column"), Json::Num(1.0));
+        let refs = start.get("references").items();
+        assert_eq!(refs.len(), 2);
+        assert_eq!(*refs[0].get("line"), Json::Num(3.0));
+        assert_eq!(*refs[1].get("line"), Json::Num(4.0));
+        assert_eq!(*refs[1].get("column"), Json::Num(6.0));
+        let segments = json.get("segments").items();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(*segments[0].get("start"), Json::Num(49152.0));
+        assert_eq!(*segments[0].get("size"), Json::Num(5.0));
+        assert_eq!(*segments[1].get("end"), Json::Num(53248.0));
+
+        let src = "\t.org 0\n\t.ds $FFFF\n\t.byte 1\n";
+        let info = assemble(source::from_str(src, "full.s"), false).unwrap();
+        let json = parse(&mut info.dump_json().chars().peekable());
+        let segments = json.get("segments").items();
+        assert_eq!(*segments[0].get("end"), Json::Num(65535.0));
+        assert_eq!(*segments[0].get("size"), Json::Num(65536.0));
+    }
+}
@@ -1,13 +1,13 @@
 //! Assembly parsing.
 
-use std::{iter::Enumerate, rc::Rc, str::Chars};
+use std::{iter::Enumerate, rc::Rc, str::CharIndices};
 
 use better_peekable::{BPeekable, BetterPeekable};
 
 use crate::{
     action::Action,
     asm::Assembler,
-    expr::ExprNode,
+    expr::{ExLab, ExprNode, Func},
     opcode::{find_op, AMode, OpCode},
     pseudo::PseudoOp,
     source::{Line, LineSlice},
@@ -18,14 +18,17 @@ pub struct ParsedLine {
     pub label: Option<Rc<LineSlice>>,
     pub action: Option<Box<dyn Action>>,
     pub comment: Option<Rc<LineSlice>>,
+    /// The size of the action as found in pass1, not counting any alignment padding.
+    pub size: u16,
 }
 
 impl ParsedLine {
-    /// If we have a comment, remove its leading ';' and strip whitespace.
+    /// If we have a comment, remove its leading ';' (or '//') and strip whitespace.
     /// Returns Some(s) if the resulting string is non-empty.
     pub fn filter_comment(&self) -> Option<&str> {
         if let Some(l) = &self.comment {
-            let s = l.text()[1..].trim();
+            let s = l.text();
+            let s = s.strip_prefix("//").unwrap_or(&s[1..]).trim();
             if s.is_empty() {
                 None
             } else {
@@ -41,14 +44,14 @@ impl ParsedLine {
 #[derive(Clone)]
 pub struct LineChars<'a> {
     line: &'a Rc<Line>,
-    chars: Enumerate<Chars<'a>>,
+    chars: Enumerate<CharIndices<'a>>,
 }
 
 impl<'a> LineChars<'a> {
     pub fn new(line: &'a Rc<Line>) -> Self {
         Self {
             line,
-            chars: line.text.chars().enumerate(),
+            chars: line.text.char_indices().enumerate(),
         }
     }
 }
@@ -57,13 +60,13 @@ impl Iterator for LineChars<'_> {
     type Item = (char, Rc<LineSlice>);
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some((char_index, c)) = self.chars.next() {
+        if let Some((char_index, (byte_index, c))) = self.chars.next() {
             Some((
                 c,
-                Rc::new(LineSlice::new(
+                Rc::new(LineSlice::with_indices(
                     self.line.clone(),
-                    char_index as u16,
-                    (char_index as u16) + 1,
+                    (char_index as u16, char_index as u16 + 1),
+                    (byte_index as u16, (byte_index + c.len_utf8()) as u16),
                 )),
             ))
         } else {
@@ -95,6 +98,7 @@ impl Assembler {
                 label,
                 action,
                 comment,
+                size: 0,
             })
         }
     }
@@ -119,14 +123,17 @@ impl Assembler {
         if let Some(name) = self.parse_name(chars) {
             if let Some((c, _)) = chars.peek() {
                 if *c == ':' {
+                    // A trailing ':' always marks a label, even if it shares a macro or opcode
+                    // name.
                     chars.next();
+                    return Ok(Some(name));
                 }
             }
             if self.macros.contains_key(name.text()) {
                 Ok(None)
             } else {
                 let opchk = name.text().to_ascii_lowercase();
-                if find_op(opchk.as_str()).is_some() {
+                if find_op(opchk.as_str(), self.cpu, self.aliases).is_some() {
                     Ok(None)
                 } else {
                     Ok(Some(name))
@@ -138,13 +145,33 @@ impl Assembler {
     }
 
     /// Grab a leading Name, if any.
+    ///
+    /// A `::` can join a struct name and a member name, as in `point::xpos`, and a `.` can
+    /// join a `.scope` name and a name inside it, as in `sound.play`.
     fn parse_name(&mut self, chars: &mut BPeekable<LineChars>) -> Option<Rc<LineSlice>> {
         if let Some((c, start)) = chars.peek().cloned() {
             if is_alpha(c) {
                 chars.next();
                 let mut end = start.end_char;
-                while let Some((c, new_end)) = chars.peek() {
-                    if !is_alphanum(*c) {
+                while let Some((c, new_end)) = chars.peek().cloned() {
+                    if c == ':' {
+                        if matches!(chars.peek_n(1), Some((':', _)))
+                            && matches!(chars.peek_n(2), Some((c, _)) if is_alpha(*c))
+                        {
+                            chars.next();
+                            chars.next();
+                            continue;
+                        }
+                        break;
+                    }
+                    if c == '.' {
+                        if matches!(chars.peek_n(1), Some((c, _)) if is_alpha(*c)) {
+                            chars.next();
+                            continue;
+                        }
+                        break;
+                    }
+                    if !is_alphanum(c) {
                         break;
                     }
                     end = new_end.end_char;
@@ -179,6 +206,21 @@ impl Assembler {
                 chars.next();
                 return self.parse_pseudo(start, chars).map(Some);
             }
+            if c == '*' {
+                // `* = {expr}` sets the PC, as `.org` does.
+                let mut n = 1;
+                while matches!(chars.peek_n(n), Some((c, _)) if c.is_ascii_whitespace()) {
+                    n += 1;
+                }
+                if let Some(('=', end)) = chars.peek_n(n).cloned() {
+                    for _ in 0..=n {
+                        chars.next();
+                    }
+                    let name = Rc::new(start.join(&end));
+                    let args = vec![self.parse_expr(chars)?];
+                    return Ok(Some(Box::new(PseudoOp::new_alias(name, ".org", args))));
+                }
+            }
         }
 
         if let Some(name) = self.parse_name(chars) {
@@ -196,10 +238,15 @@ impl Assembler {
     /// (skips whitespace and also exits on a comment).
     pub fn at_eol(&mut self, chars: &mut BPeekable<LineChars>) -> bool {
         self.skip_ws(chars);
-        if let Some((c, _)) = chars.peek() {
-            *c == ';'
-        } else {
-            true
+        self.at_comment(chars) || chars.peek().is_none()
+    }
+
+    /// Return a flag if we're at the start of a `;` or `//` comment.
+    fn at_comment(&mut self, chars: &mut BPeekable<LineChars>) -> bool {
+        match chars.peek() {
+            Some((';', _)) => true,
+            Some(('/', _)) => matches!(chars.peek_n(1), Some(('/', _))),
+            _ => false,
         }
     }
 
@@ -210,15 +257,30 @@ impl Assembler {
         chars: &mut BPeekable<LineChars>,
     ) -> Result<Box<dyn Action>, String> {
         if let Some(name) = self.parse_name(chars) {
-            let name = Rc::new(start.join(&name));
+            let mut name = Rc::new(start.join(&name));
+            if name.text().eq_ignore_ascii_case(".warn") && matches!(chars.peek(), Some(('-', _))) {
+                // `.warn-{name}` turns a warning on or off, as in `.warn-jmpbug on`.
+                chars.next();
+                let Some(warning) = self.parse_name(chars) else {
+                    return name.err("expected a warning name after '.warn-'");
+                };
+                name = Rc::new(name.join(&warning));
+            }
+            if name.text().eq_ignore_ascii_case(".func") {
+                return self.parse_func(name, chars);
+            }
+            let lcase = name.text().to_ascii_lowercase();
+            if lcase == ".byte.for" || lcase == ".word.for" {
+                return self.parse_for(name, chars);
+            }
             if self.at_eol(chars) {
                 Ok(Box::new(PseudoOp::new(name, Vec::new())))
             } else {
-                let mut args = vec![self.parse_expr(chars)?];
+                let mut args = vec![self.parse_pseudo_arg(chars)?];
                 while let Some((c, _)) = chars.peek() {
                     if *c == ',' {
                         chars.next();
-                        args.push(self.parse_expr(chars)?);
+                        args.push(self.parse_pseudo_arg(chars)?);
                     } else {
                         break;
                     }
@@ -230,6 +292,114 @@ impl Assembler {
         }
     }
 
+    /// Parse a `.func {name}({param}, ...) = {expr}` definition.
+    ///
+    /// The pseudo-op's one argument is the function, as a call with no arguments named by
+    /// the function's name; it's defined in pass1, in time for the following lines to call it.
+    fn parse_func(
+        &mut self,
+        op_name: Rc<LineSlice>,
+        chars: &mut BPeekable<LineChars>,
+    ) -> Result<Box<dyn Action>, String> {
+        self.skip_ws(chars);
+        let Some(name) = self.parse_name(chars) else {
+            return op_name.err("expected function name");
+        };
+        self.skip_ws(chars);
+        if !matches!(chars.next(), Some(('(', _))) {
+            return name.err("expected '(' and parameters after function name");
+        }
+        let mut params: Vec<String> = Vec::new();
+        self.skip_ws(chars);
+        if matches!(chars.peek(), Some((')', _))) {
+            chars.next();
+        } else {
+            loop {
+                self.skip_ws(chars);
+                let Some(param) = self.parse_name(chars) else {
+                    return name.err("expected parameter name");
+                };
+                if params.iter().any(|p| p == param.text()) {
+                    return param.err(&format!("parameter '{}' repeated", param.text()));
+                }
+                params.push(param.text().to_string());
+                self.skip_ws(chars);
+                match chars.next() {
+                    Some((',', _)) => (),
+                    Some((')', _)) => break,
+                    _ => return name.err("missing closing ')'"),
+                }
+            }
+        }
+        self.skip_ws(chars);
+        if !matches!(chars.next(), Some(('=', _))) {
+            return name.err("expected '=' and the function's expression");
+        }
+        self.func_params = Some(params);
+        let body = self.parse_expr(chars);
+        let params = self.func_params.take().unwrap();
+        let func = Rc::new(Func {
+            params,
+            body: body?,
+        });
+        let def = ExprNode::new(ExLab::Call(func, Vec::new()), name);
+        Ok(Box::new(PseudoOp::new(op_name, vec![def])))
+    }
+
+    /// Parse a `.byte.for {var}, {first}, {last} : {expr}` (or `.word.for`) table.
+    ///
+    /// The pseudo-op's arguments are `first`, `last`, and `expr`, in which `var` is a
+    /// parameter, as in a `.func`: it's given each index in turn.
+    fn parse_for(
+        &mut self,
+        op_name: Rc<LineSlice>,
+        chars: &mut BPeekable<LineChars>,
+    ) -> Result<Box<dyn Action>, String> {
+        self.skip_ws(chars);
+        let Some(var) = self.parse_name(chars) else {
+            return op_name.err("expected loop variable name");
+        };
+        let mut bounds = Vec::with_capacity(2);
+        for _ in 0..2 {
+            self.skip_ws(chars);
+            if !matches!(chars.next(), Some((',', _))) {
+                return op_name.err("expected ',' and the index's first and last values");
+            }
+            bounds.push(self.parse_expr(chars)?);
+        }
+        self.skip_ws(chars);
+        if !matches!(chars.next(), Some((':', _))) {
+            return op_name.err("expected ':' and the expression for each index");
+        }
+        self.func_params = Some(vec![var.text().to_string()]);
+        let expr = self.parse_expr(chars);
+        self.func_params = None;
+        bounds.push(expr?);
+        Ok(Box::new(PseudoOp::new(op_name, bounds)))
+    }
+
+    /// Parse a pseudo-op argument: an expression, possibly followed by `dup {count}`.
+    fn parse_pseudo_arg(
+        &mut self,
+        chars: &mut BPeekable<LineChars>,
+    ) -> Result<Box<ExprNode>, String> {
+        let value = self.parse_expr(chars)?;
+        self.skip_ws(chars);
+        let is_dup = matches!(chars.peek(), Some((c, _)) if c.eq_ignore_ascii_case(&'d'))
+            && matches!(chars.peek_n(1), Some((c, _)) if c.eq_ignore_ascii_case(&'u'))
+            && matches!(chars.peek_n(2), Some((c, _)) if c.eq_ignore_ascii_case(&'p'))
+            && !matches!(chars.peek_n(3), Some((c, _)) if is_alphanum(*c));
+        if !is_dup {
+            return Ok(value);
+        }
+        for _ in 0..3 {
+            chars.next();
+        }
+        let count = self.parse_expr(chars)?;
+        let slice = Rc::new(value.slice.join(&count.slice));
+        Ok(ExprNode::new(ExLab::Dup(value, count), slice))
+    }
+
     /// Parse an opcode.
     fn parse_opcode(
         &mut self,
@@ -237,7 +407,19 @@ impl Assembler {
         chars: &mut BPeekable<LineChars>,
     ) -> Result<Box<dyn Action>, String> {
         let op_name = opcode.text().to_ascii_lowercase();
-        if let Some(op) = find_op(&op_name) {
+        if let Some(op) = find_op(&op_name, self.cpu, self.aliases) {
+            if op.is_bit_branch() {
+                let zp = self.parse_expr(chars)?;
+                self.skip_ws(chars);
+                if !matches!(chars.next(), Some((',', _))) {
+                    return zp.slice.err(&format!(
+                        "'{}' needs a zero page address, then ',' and a branch target",
+                        opcode.text()
+                    ));
+                }
+                let target = self.parse_expr(chars)?;
+                return Ok(Box::new(OpCode::new_bit_branch(op, opcode, zp, target)));
+            }
             let (amode, expr) = self.parse_operand(chars)?;
             Ok(Box::new(OpCode::new(op, opcode, amode, expr)))
         } else {
@@ -260,19 +442,23 @@ impl Assembler {
             }
         };
 
-        match chars.peek() {
-            Some(('a', _)) | Some(('A', _)) => {
-                if let Some((c, _)) = chars.peek_n(1) {
-                    if c.is_whitespace() || *c == ';' {
-                        chars.next().unwrap();
-                        return Ok((AMode::Imp, None));
-                    }
-                } else {
-                    chars.next().unwrap();
-                    return Ok((AMode::Imp, None));
-                }
+        // The accumulator, written explicitly, is only `A` when it's the whole operand: the
+        // rest of the line must be blank or a comment, so `asl alpha` and `lda a+1` are
+        // addresses.
+        if let Some(('a', _)) | Some(('A', _)) = chars.peek() {
+            let mut n = 1;
+            while matches!(chars.peek_n(n), Some((c, _)) if c.is_whitespace()) {
+                n += 1;
+            }
+            let is_acc = match chars.peek_n(n) {
+                None | Some((';', _)) => true,
+                Some(('/', _)) => matches!(chars.peek_n(n + 1), Some(('/', _))),
+                _ => false,
+            };
+            if is_acc {
+                chars.next().unwrap();
+                return Ok((AMode::Imp, None));
             }
-            _ => (),
         }
 
         if let Some((c, _)) = chars.peek() {
@@ -281,13 +467,16 @@ impl Assembler {
                     chars.next();
                     return Ok((AMode::Imm, Some(self.parse_expr(chars)?)));
                 }
-                '(' => {
+                // Indirect modes may be bracketed as well, as `[...]` can't be confused with
+                // a parenthesized expression.
+                '(' | '[' => {
+                    let close = if *c == '(' { ')' } else { ']' };
                     chars.next();
                     let expr = self.parse_expr(chars)?;
                     self.skip_ws(chars);
                     if let Some((c, _)) = chars.peek() {
-                        match c {
-                            ')' => {
+                        match *c {
+                            c if c == close => {
                                 chars.next();
                                 self.skip_ws(chars);
                                 if let Some((c, _)) = chars.peek() {
@@ -315,7 +504,7 @@ impl Assembler {
                                     chars.next();
                                     self.skip_ws(chars);
                                     if let Some((rparen, _)) = chars.peek() {
-                                        if *rparen == ')' {
+                                        if *rparen == close {
                                             chars.next();
                                             match c {
                                                 'x' | 'X' => return Ok((AMode::IndX, Some(expr))),
@@ -368,8 +557,8 @@ impl Assembler {
         chars: &mut BPeekable<LineChars>,
     ) -> Result<Option<Rc<LineSlice>>, String> {
         self.skip_ws(chars);
-        if let Some((c, start)) = chars.peek().cloned() {
-            if c == ';' {
+        if let Some((_, start)) = chars.peek().cloned() {
+            if self.at_comment(chars) {
                 if let Some((_, end)) = chars.last() {
                     Ok(Some(Rc::new(start.join(&end))))
                 } else {
@@ -394,7 +583,7 @@ pub fn is_alpha(c: char) -> bool {
 
 /// Return a flag if the character counts as alphabetic or numeric.
 #[inline]
-fn is_alphanum(c: char) -> bool {
+pub fn is_alphanum(c: char) -> bool {
     c.is_ascii_alphanumeric() || c == '_'
 }
 
@@ -411,6 +600,27 @@ mod tests {
         source::{self, Line, LineSlice},
     };
 
+    #[test]
+    fn test_line_chars() {
+        let text = (0..2000).map(|i| format!("n{i},")).collect::<String>();
+        let line = Rc::new(Line::new(&text, "long", 1));
+        let slices: Vec<_> = LineChars::new(&line).collect();
+        assert_eq!(slices.len(), text.len());
+        for (i, (c, slice)) in slices.iter().enumerate() {
+            assert_eq!(slice.text(), c.to_string());
+            if i % 97 == 0 {
+                let walked = LineSlice::new(line.clone(), i as u16, i as u16 + 1);
+                assert_eq!(**slice, walked);
+            }
+        }
+        let (_, first) = &slices[text.len() - 6];
+        let (_, last) = &slices[text.len() - 2];
+        assert_eq!(first.join(last).text(), "n1999");
+        assert_eq!(last.join(first).text(), "n1999");
+        assert_eq!(first.with_end(first.end_char + 2).text(), "n19");
+        assert_eq!(first.with_end(first.start_char).text(), "");
+    }
+
     #[test]
     fn test_parse_name() {
         let test = source::from_str("foo\nbar foobar\n\nfoobar\n", "foobar");
@@ -443,6 +653,27 @@ mod tests {
             asm.parse_name(&mut LineChars::new(&foobar).better_peekable()),
             Some(Rc::new(LineSlice::new(foobar, 0, 6)))
         );
+
+        let scoped = Rc::new(Line::new("foo::bar: foo:: foo::1", "foobar", 5));
+        let mut scoped_chars = LineChars::new(&scoped).better_peekable();
+        assert_eq!(
+            asm.parse_name(&mut scoped_chars),
+            Some(Rc::new(LineSlice::new(scoped.clone(), 0, 8)))
+        );
+        assert_eq!(scoped_chars.next().map(|(c, _)| c), Some(':'));
+        scoped_chars.next();
+        assert_eq!(
+            asm.parse_name(&mut scoped_chars),
+            Some(Rc::new(LineSlice::new(scoped.clone(), 10, 13)))
+        );
+        assert_eq!(scoped_chars.next().map(|(c, _)| c), Some(':'));
+        scoped_chars.next();
+        scoped_chars.next();
+        assert_eq!(
+            asm.parse_name(&mut scoped_chars),
+            Some(Rc::new(LineSlice::new(scoped.clone(), 16, 19)))
+        );
+        assert_eq!(scoped_chars.next().map(|(c, _)| c), Some(':'));
     }
 
     #[test]
@@ -453,4 +684,43 @@ mod tests {
         let data = assemble_str(src, "{src}").unwrap();
         assert_eq!(data, vec![0x4A, 0x6A, 0x0A]);
     }
+
+    #[test]
+    fn test_bracket_indirect() {
+        let brackets = "lda [$10],y
+        lda [ $10 , x ]
+        jmp [$1234]";
+        let parens = "lda ($10),y
+        lda ( $10 , x )
+        jmp ($1234)";
+        assert_eq!(
+            assemble_str(brackets, "{src}"),
+            Ok(vec![0xB1, 0x10, 0xA1, 0x10, 0x6C, 0x34, 0x12])
+        );
+        assert_eq!(
+            assemble_str(brackets, "{src}"),
+            assemble_str(parens, "{src}")
+        );
+        assert!(assemble_str("lda [$10),y", "{src}").is_err());
+        assert!(assemble_str("lda ($10],y", "{src}").is_err());
+    }
+
+    #[test]
+    fn test_accumulator() {
+        let src = "alpha   = $1234
+a       = $10
+        asl a
+        asl alpha
+        lsr a ;comment
+        rol A   // comment
+        ror a+1
+        asl a , x
+        asl";
+        assert_eq!(
+            assemble_str(src, "{src}"),
+            Ok(vec![
+                0x0A, 0x0E, 0x34, 0x12, 0x4A, 0x2A, 0x66, 0x11, 0x16, 0x10, 0x0A
+            ])
+        );
+    }
 }
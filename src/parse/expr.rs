@@ -6,10 +6,11 @@ use better_peekable::BPeekable;
 
 use crate::{
     asm::Assembler,
-    expr::{ExLab, ExprNode, RelOp},
+    expr::{ExLab, ExprNode, Func, RelOp},
+    source::{self, LineSlice},
 };
 
-use super::{is_alpha, LineChars};
+use super::{is_alpha, is_alphanum, LineChars};
 
 impl Assembler {
     /// Assemble an expression.
@@ -17,7 +18,65 @@ impl Assembler {
         &mut self,
         chars: &mut BPeekable<LineChars>,
     ) -> Result<Box<ExprNode>, String> {
-        self.parse_hilo(chars)
+        self.parse_ternary(chars)
+    }
+
+    /// Parse a `cond ? a : b` expression.
+    fn parse_ternary(&mut self, chars: &mut BPeekable<LineChars>) -> Result<Box<ExprNode>, String> {
+        let cond = self.parse_logical_or(chars)?;
+
+        self.skip_ws(chars);
+        if let Some(('?', start)) = chars.peek().cloned() {
+            chars.next();
+            let left = self.parse_ternary(chars)?;
+            self.skip_ws(chars);
+            if let Some((':', _)) = chars.peek() {
+                chars.next();
+                let right = self.parse_ternary(chars)?;
+                let slice = Rc::new(cond.slice.join(&right.slice));
+                Ok(ExprNode::new(ExLab::Ternary(cond, left, right), slice))
+            } else {
+                start.err("missing ':' for '?'")
+            }
+        } else {
+            Ok(cond)
+        }
+    }
+
+    /// Parse a '||' expression.
+    fn parse_logical_or(
+        &mut self,
+        chars: &mut BPeekable<LineChars>,
+    ) -> Result<Box<ExprNode>, String> {
+        let mut e = self.parse_logical_and(chars)?;
+        self.skip_ws(chars);
+        while matches!(chars.peek(), Some(('|', _))) && matches!(chars.peek_n(1), Some(('|', _))) {
+            chars.next();
+            chars.next();
+            let right = self.parse_logical_and(chars)?;
+            let slice = Rc::new(e.slice.join(&right.slice));
+            e = ExprNode::new(ExLab::LogicalOr(e, right), slice);
+            self.skip_ws(chars);
+        }
+        Ok(e)
+    }
+
+    /// Parse a '&&' expression.
+    fn parse_logical_and(
+        &mut self,
+        chars: &mut BPeekable<LineChars>,
+    ) -> Result<Box<ExprNode>, String> {
+        let mut e = self.parse_hilo(chars)?;
+        self.skip_ws(chars);
+        while matches!(chars.peek(), Some(('&', _))) && matches!(chars.peek_n(1), Some(('&', _))) {
+            chars.next();
+            chars.next();
+            let right = self.parse_hilo(chars)?;
+            let slice = Rc::new(e.slice.join(&right.slice));
+            e = ExprNode::new(ExLab::LogicalAnd(e, right), slice);
+            self.skip_ws(chars);
+        }
+        Ok(e)
     }
 
     /// Parse a >/< expression.
@@ -80,10 +139,34 @@ impl Assembler {
                 }
                 '=' => {
                     chars.next().unwrap();
-                    (RelOp::Equ, start)
+                    match chars.peek().cloned() {
+                        Some(('=', end)) => {
+                            chars.next().unwrap();
+                            (RelOp::Equ, Rc::new(start.join(&end)))
+                        }
+                        _ => (RelOp::Equ, start),
+                    }
+                }
+                '!' => {
+                    chars.next().unwrap();
+                    match chars.next() {
+                        Some(('=', end)) => (RelOp::Nequ, Rc::new(start.join(&end))),
+                        _ => return start.err("expected '!='"),
+                    }
                 }
                 _ => break,
             };
+            let op = if self.signed {
+                match op {
+                    RelOp::Less => RelOp::SLess,
+                    RelOp::Great => RelOp::SGreat,
+                    RelOp::LessEqu => RelOp::SLessEqu,
+                    RelOp::GreatEqu => RelOp::SGreatEqu,
+                    op => op,
+                }
+            } else {
+                op
+            };
             let right = self.parse_addsub(chars)?;
             e = ExprNode::new(ExLab::RelOp(op, e, right), slice);
         }
@@ -96,8 +179,10 @@ impl Assembler {
         let mut e = self.parse_addsub(chars)?;
 
         self.skip_ws(chars);
-        while let Some((c, _)) = chars.peek() {
+        while let Some(c) = chars.peek().map(|(c, _)| *c) {
             match c {
+                // Doubled, these are the logical operators instead.
+                '&' | '|' if matches!(chars.peek_n(1), Some((d, _)) if *d == c) => break,
                 '&' => {
                     chars.next();
                     let right = self.parse_addsub(chars)?;
@@ -149,8 +234,10 @@ impl Assembler {
     fn parse_muldiv(&mut self, chars: &mut BPeekable<LineChars>) -> Result<Box<ExprNode>, String> {
         let mut e = self.parse_unary(chars)?;
         self.skip_ws(chars);
-        while let Some((c, _)) = chars.peek() {
+        while let Some(c) = chars.peek().map(|(c, _)| *c) {
             match c {
+                // '//' starts a comment instead.
+                '/' if matches!(chars.peek_n(1), Some(('/', _))) => break,
                 '*' => {
                     chars.next();
                     let right = self.parse_unary(chars)?;
@@ -162,14 +249,22 @@ impl Assembler {
                     chars.next();
                     let right = self.parse_unary(chars)?;
                     let slice = Rc::new(e.slice.join(&right.slice));
-                    e = ExprNode::new(ExLab::Div(e, right), slice);
+                    e = if self.signed {
+                        ExprNode::new(ExLab::SDiv(e, right), slice)
+                    } else {
+                        ExprNode::new(ExLab::Div(e, right), slice)
+                    };
                     self.skip_ws(chars);
                 }
                 '%' => {
                     chars.next();
                     let right = self.parse_unary(chars)?;
                     let slice = Rc::new(e.slice.join(&right.slice));
-                    e = ExprNode::new(ExLab::Mod(e, right), slice);
+                    e = if self.signed {
+                        ExprNode::new(ExLab::SMod(e, right), slice)
+                    } else {
+                        ExprNode::new(ExLab::Mod(e, right), slice)
+                    };
                     self.skip_ws(chars);
                 }
                 _ => break,
@@ -210,26 +305,45 @@ impl Assembler {
                 }
                 start.err("missing closing ')'")
             } else if c.is_ascii_digit() {
-                self.parse_num(10, chars)
+                self.parse_decimal(chars)
             } else if c == '$' {
                 chars.next();
-                self.parse_num(16, chars)
+                self.parse_num(16, start, chars)
             } else if c == '%' {
                 chars.next();
-                self.parse_num(2, chars)
+                self.parse_num(2, start, chars)
             } else if c == '@' {
                 chars.next();
-                self.parse_num(8, chars)
+                self.parse_num(8, start, chars)
             } else if is_alpha(c) {
                 let name = self.parse_name(chars).unwrap();
+                if let Some(i) = self
+                    .func_params
+                    .as_ref()
+                    .and_then(|params| params.iter().position(|p| p == name.text()))
+                {
+                    return Ok(ExprNode::new(ExLab::Param(i), name));
+                }
+                if let Some(func) = self.funcs.get(name.text()).cloned() {
+                    return self.parse_call(name, func, chars);
+                }
+                if name.text().eq_ignore_ascii_case("filesize") {
+                    self.skip_ws(chars);
+                    if let Some(('(', _)) = chars.peek() {
+                        return self.parse_filesize(name, chars);
+                    }
+                }
                 // Ensure this reference to the symbol is noticed
-                self.lookup(name.text(), name.clone());
+                self.lookup_ref(name.text(), name.clone());
                 Ok(ExprNode::new(ExLab::Name, name))
             } else if c == '\'' || c == '"' {
                 self.parse_str(chars)
             } else if c == '*' {
                 chars.next();
-                Ok(ExprNode::new(ExLab::Num(self.pc), start))
+                Ok(ExprNode::new(ExLab::Num(self.pc() as u32), start))
+            } else if c == '.' {
+                chars.next();
+                self.parse_builtin(start, chars)
             } else {
                 start.err("Missing primary expression")
             }
@@ -241,50 +355,295 @@ impl Assembler {
         }
     }
 
-    /// Parse a number in a given base.
-    fn parse_num(
+    /// Parse the name of a builtin after its `.`, evaluating it immediately.
+    ///
+    /// `.line` is the current line number, and `.file` a 16-bit hash of the current
+    /// file's path. They're not symbols, so they can't be redefined. `.defined(NAME)` is 1
+    /// if the symbol was given a value before this line, otherwise 0; it isn't a reference
+    /// to it.
+    fn parse_builtin(
         &mut self,
-        base: u8,
+        start: Rc<LineSlice>,
         chars: &mut BPeekable<LineChars>,
     ) -> Result<Box<ExprNode>, String> {
-        let (c, start) = chars.peek().unwrap();
-        let mut i = {
-            if let Some(i) = c.to_digit(base as u32) {
-                i as u16
-            } else {
-                return self
-                    .cur_line
-                    .as_ref()
-                    .unwrap()
-                    .err(&format!("'{}' isn't a digit in base {}", c, base));
+        let Some(name) = self.parse_name(chars) else {
+            return start.err("expected builtin name after '.'");
+        };
+        let slice = Rc::new(start.join(&name));
+        let line = self.cur_line.as_ref().unwrap();
+        let value = match name.text().to_ascii_lowercase().as_str() {
+            "line" => line.line_num,
+            "file" => path_hash(&line.path),
+            "defined" => return self.parse_defined(slice, chars),
+            _ => return slice.err(&format!("unknown builtin '.{}'", name.text())),
+        };
+        Ok(ExprNode::new(ExLab::Num(value), slice))
+    }
+
+    /// Parse the `(NAME)` of a `.defined(NAME)`, evaluating it immediately.
+    fn parse_defined(
+        &mut self,
+        start: Rc<LineSlice>,
+        chars: &mut BPeekable<LineChars>,
+    ) -> Result<Box<ExprNode>, String> {
+        self.skip_ws(chars);
+        if !matches!(chars.next(), Some(('(', _))) {
+            return start.err("expected '(' after '.defined'");
+        }
+        self.skip_ws(chars);
+        let Some(name) = self.parse_name(chars) else {
+            return start.err("expected symbol name in '.defined'");
+        };
+        self.skip_ws(chars);
+        let Some((')', end)) = chars.next() else {
+            return start.err("missing closing ')'");
+        };
+        let defined = self.resolve(name.text()).is_some();
+        Ok(ExprNode::new(
+            ExLab::Num(defined as u32),
+            Rc::new(start.join(&end)),
+        ))
+    }
+
+    /// Parse the arguments of a call of a `.func`, after its name.
+    fn parse_call(
+        &mut self,
+        name: Rc<LineSlice>,
+        func: Rc<Func>,
+        chars: &mut BPeekable<LineChars>,
+    ) -> Result<Box<ExprNode>, String> {
+        self.skip_ws(chars);
+        if !matches!(chars.next(), Some(('(', _))) {
+            return name.err(&format!("expected '(' after function '{}'", name.text()));
+        }
+        let mut args = Vec::new();
+        self.skip_ws(chars);
+        if !matches!(chars.peek(), Some((')', _))) {
+            loop {
+                args.push(self.parse_expr(chars)?);
+                self.skip_ws(chars);
+                if !matches!(chars.peek(), Some((',', _))) {
+                    break;
+                }
+                chars.next();
+            }
+        }
+        let Some((')', end)) = chars.next() else {
+            return name.err("missing closing ')'");
+        };
+        let slice = Rc::new(name.join(&end));
+        if args.len() != func.params.len() {
+            return slice.err(&format!(
+                "'{}' takes {} arguments, got {}",
+                name.text(),
+                func.params.len(),
+                args.len()
+            ));
+        }
+        Ok(ExprNode::new(ExLab::Call(func, args), slice))
+    }
+
+    /// Parse the rest of a `filesize("path")` function, evaluating it immediately.
+    fn parse_filesize(
+        &mut self,
+        name: Rc<LineSlice>,
+        chars: &mut BPeekable<LineChars>,
+    ) -> Result<Box<ExprNode>, String> {
+        let arg = self.parse_primary(chars)?;
+        let path = match &arg.label {
+            ExLab::Expr(e) => match &e.label {
+                ExLab::Str(s) => s.clone(),
+                ExLab::Char(c) => char::from(*c).to_string(),
+                _ => return arg.slice.err("expected filename"),
+            },
+            _ => return arg.slice.err("expected filename"),
+        };
+        let slice = Rc::new(name.join(&arg.slice));
+        let resolver = self.resolver.as_ref();
+        match resolver.size(&source::resolve_path(&path, slice.path(), resolver)) {
+            Ok(len) => {
+                if let Ok(size) = u32::try_from(len) {
+                    Ok(ExprNode::new(ExLab::Num(size), slice))
+                } else {
+                    slice.err(&format!("'{}' is too large ({} bytes)", path, len))
+                }
             }
+            Err(e) => slice.err(&format!("error reading '{}': {}", path, e)),
+        }
+    }
+
+    /// Parse a number in a given base, after its prefix.
+    fn parse_num(
+        &mut self,
+        base: u32,
+        prefix: Rc<LineSlice>,
+        chars: &mut BPeekable<LineChars>,
+    ) -> Result<Box<ExprNode>, String> {
+        let (digits, slice) = self.take_chars(chars, |c| c == '_' || c.is_digit(base));
+        let Some(slice) = slice else {
+            return self.cur_line.as_ref().unwrap().err(&match chars.peek() {
+                Some((c, _)) => format!("'{}' isn't a digit in base {}", c, base),
+                None => format!("missing digits in base {}", base),
+            });
+        };
+        let slice = Rc::new(prefix.join(&slice));
+        let i = num_value(&digits, base, &slice)?;
+        Ok(ExprNode::new(ExLab::Num(i), slice))
+    }
+
+    /// Parse a number starting with a decimal digit, which may have a C-style prefix or a
+    /// trailing radix.
+    ///
+    /// In order, the number is:
+    /// - hexadecimal if it ends with `h` (`0FFh`),
+    /// - hexadecimal if it starts with `0x` (`0xFF`),
+    /// - binary if it starts with `0b` and has more digits (`0b1010`),
+    /// - binary if it ends with `b` and the rest is all `0`/`1` (`1010b`, `0b`),
+    /// - otherwise decimal.
+    ///
+    /// A hexadecimal number with a trailing radix must start with a decimal digit, or else
+    /// it's a name (`FFh`).
+    fn parse_decimal(&mut self, chars: &mut BPeekable<LineChars>) -> Result<Box<ExprNode>, String> {
+        let (token, slice) = self.take_chars(chars, is_alphanum);
+        let slice = slice.unwrap();
+        let token = token.to_ascii_lowercase();
+        let (digits, base) = if let Some(digits) = token.strip_suffix('h') {
+            (digits, 16)
+        } else if let Some(digits) = token.strip_prefix("0x") {
+            (digits, 16)
+        } else if token.len() > 2 && token.starts_with("0b") {
+            (&token[2..], 2)
+        } else if let Some(digits) = token
+            .strip_suffix('b')
+            .filter(|d| d.chars().all(|c| matches!(c, '0' | '1' | '_')))
+        {
+            (digits, 2)
+        } else {
+            (token.as_str(), 10)
         };
-        let mut slice = start.clone();
-        chars.next();
+        let i = num_value(digits, base, &slice)?;
+        Ok(ExprNode::new(ExLab::Num(i), slice))
+    }
+
+    /// Take characters while they match a predicate, returning them and their joined slice
+    /// (if any).
+    fn take_chars(
+        &mut self,
+        chars: &mut BPeekable<LineChars>,
+        pred: impl Fn(char) -> bool,
+    ) -> (String, Option<Rc<LineSlice>>) {
+        let mut s = String::new();
+        let mut slice: Option<Rc<LineSlice>> = None;
         while let Some((c, end)) = chars.peek() {
-            if let Some(digit) = c.to_digit(base as u32) {
-                i = i * (base as u16) + (digit as u16);
-                slice = Rc::new(slice.join(end));
-                chars.next();
-            } else {
+            if !pred(*c) {
                 break;
             }
+            s.push(*c);
+            slice = Some(match slice {
+                Some(start) => Rc::new(start.join(end)),
+                None => end.clone(),
+            });
+            chars.next();
         }
-        Ok(ExprNode::new(ExLab::Num(i), slice))
+        (s, slice)
     }
 
-    /// Parse a string.
+    /// Parse a string or character literal.
     fn parse_str(&mut self, chars: &mut BPeekable<LineChars>) -> Result<Box<ExprNode>, String> {
         let (quote, start) = chars.next().unwrap();
         let mut s = String::new();
-        for (c, end) in chars.by_ref() {
+        while let Some((c, end)) = chars.next() {
             if c == quote {
                 let slice = Rc::new(start.join(&end));
-                return Ok(ExprNode::new(ExLab::Str(s), slice));
+                // A single-byte string is a character literal.
+                let label = match s.as_bytes() {
+                    [c] => ExLab::Char(*c),
+                    _ => ExLab::Str(s),
+                };
+                return Ok(ExprNode::new(label, slice));
+            } else if c == '\\' {
+                s.push(self.parse_escape(&end, chars)?);
             } else {
                 s.push(c);
             }
         }
         start.err(&format!("missing closing quote: {quote}"))
     }
+
+    /// Parse the rest of a string escape, after its leading backslash.
+    fn parse_escape(
+        &mut self,
+        start: &LineSlice,
+        chars: &mut BPeekable<LineChars>,
+    ) -> Result<char, String> {
+        match chars.next() {
+            Some(('n', _)) => Ok('\n'),
+            Some(('r', _)) => Ok('\r'),
+            Some(('t', _)) => Ok('\t'),
+            Some(('0', _)) => Ok('\0'),
+            Some(('\\', _)) => Ok('\\'),
+            Some(('"', _)) => Ok('"'),
+            Some(('\'', _)) => Ok('\''),
+            Some(('x', _)) => {
+                let mut value = 0;
+                for _ in 0..2 {
+                    if let Some(digit) = chars.peek().and_then(|(c, _)| c.to_digit(16)) {
+                        value = value * 16 + digit;
+                        chars.next();
+                    } else {
+                        return start.err("expected two hex digits after '\\x'");
+                    }
+                }
+                if value > 0x7F {
+                    start.err("'\\x' escapes must be in the range 00-7F")
+                } else {
+                    Ok(char::from(value as u8))
+                }
+            }
+            Some((c, _)) => start.err(&format!("unknown string escape '\\{c}'")),
+            None => start.err("missing string escape"),
+        }
+    }
+}
+
+/// Get the value of a number's digits in the given base.
+///
+/// Single `_` characters may separate the digits.
+fn num_value(digits: &str, base: u32, slice: &Rc<LineSlice>) -> Result<u32, String> {
+    if digits.is_empty() {
+        return slice.err(&format!("missing digits in base {}", base));
+    }
+    if digits.contains("__") {
+        return slice.err("doubled '_' in number");
+    }
+    if digits.ends_with('_') {
+        return slice.err("number ends with '_'");
+    }
+    let mut i: u32 = 0;
+    for (n, c) in digits.chars().enumerate() {
+        if c == '_' && n > 0 {
+            continue;
+        }
+        let Some(digit) = c.to_digit(base) else {
+            return slice.err(&format!("'{}' isn't a digit in base {}", c, base));
+        };
+        if let Some(new_i) = i.checked_mul(base).and_then(|i| i.checked_add(digit)) {
+            i = new_i;
+        } else {
+            return slice.err("number too large");
+        }
+    }
+    Ok(i)
+}
+
+/// Hash a file path down to 16 bits, with FNV-1a folded in half.
+///
+/// This is stable between builds, so it's safe to embed in the output.
+fn path_hash(path: &str) -> u32 {
+    let mut hash: u32 = 0x811C_9DC5;
+    for b in path.bytes() {
+        hash ^= b as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    (hash >> 16) ^ (hash & 0xFFFF)
 }
@@ -1,14 +1,18 @@
 //! Pseudo-Op support.
 
-use std::{fs, rc::Rc};
+use std::{ops::RangeInclusive, rc::Rc};
 
 use crate::{
     action::Action,
-    asm::Assembler,
+    asm::{Assembler, Cond, Pass},
     expr::{ExLab, ExprNode},
+    opcode::Cpu,
     source::{self, LineSlice},
 };
 
+/// The address of the 6502's vectors: NMI, then RESET, then IRQ/BRK.
+const VECTORS: u16 = 0xFFFA;
+
 /// Indicates a pseudo-op.
 pub struct PseudoOp {
     op_name: Rc<LineSlice>,
@@ -28,17 +32,198 @@ impl PseudoOp {
         }
     }
 
+    /// Create a pseudo-op written as something other than its name, such as `*=` for `.org`.
+    #[allow(clippy::vec_box)]
+    pub fn new_alias(op_name: Rc<LineSlice>, name: &str, args: Vec<Box<ExprNode>>) -> Self {
+        Self {
+            op_name,
+            args,
+            op_name_lcase: name.to_string(),
+        }
+    }
+
     fn arg_count_err<T>(&self) -> Result<T, String> {
         self.line_slice().err("incorrect number of arguments")
     }
 
+    /// Return the number of bytes the `.byte`-style arguments will emit.
+    fn byte_args_size(&self, assembler: &mut Assembler) -> Result<u16, String> {
+        let mut sum: u16 = 0;
+        for arg in &self.args {
+            let (arg, count) = Self::dup_arg(arg, assembler)?;
+            let size = match Self::is_str_arg(arg) {
+                Some(s) => u16::try_from(s.len()).ok(),
+                None => Some(1),
+            };
+            sum = size
+                .and_then(|size| size.checked_mul(count))
+                .and_then(|size| sum.checked_add(size))
+                .map_or_else(|| self.too_many_bytes(), Ok)?;
+        }
+        Ok(sum)
+    }
+
+    /// Return the number of bytes the `.word` arguments will emit.
+    fn word_args_size(&self, assembler: &mut Assembler) -> Result<u16, String> {
+        let mut sum: u16 = 0;
+        for arg in &self.args {
+            let (_, count) = Self::dup_arg(arg, assembler)?;
+            sum = count
+                .checked_mul(2)
+                .and_then(|size| sum.checked_add(size))
+                .map_or_else(|| self.too_many_bytes(), Ok)?;
+        }
+        Ok(sum)
+    }
+
+    fn too_many_bytes<T>(&self) -> Result<T, String> {
+        self.line_slice().err("too many bytes")
+    }
+
+    /// Return the index range of a `.byte.for`/`.word.for`, from its first two arguments.
+    ///
+    /// The range must be evaluated in the first pass (no forward references).
+    fn for_range(&self, assembler: &mut Assembler) -> Result<RangeInclusive<u32>, String> {
+        let first = self.args[0].eval_word(assembler)? as u32;
+        let last = self.args[1].eval_word(assembler)? as u32;
+        if last < first {
+            return self
+                .line_slice()
+                .err(&format!("last index {last} is before the first, {first}"));
+        }
+        Ok(first..=last)
+    }
+
+    /// Evaluate the expression of a `.byte.for`/`.word.for` with `eval`, for each index.
+    fn for_values<T>(
+        &self,
+        assembler: &mut Assembler,
+        eval: fn(&ExprNode, &mut Assembler) -> Result<T, String>,
+    ) -> Result<Vec<T>, String> {
+        let mut values = Vec::new();
+        for i in self.for_range(assembler)? {
+            assembler.func_args.push(vec![i]);
+            let value = eval(&self.args[2], assembler);
+            assembler.func_args.pop();
+            values.push(value?);
+        }
+        Ok(values)
+    }
+
+    /// Split a `{value} dup {count}` argument into its value and count; any other argument
+    /// has a count of 1.
+    ///
+    /// The count must be evaluated in the first pass (no forward references).
+    fn dup_arg<'a>(
+        arg: &'a ExprNode,
+        assembler: &mut Assembler,
+    ) -> Result<(&'a ExprNode, u16), String> {
+        if let ExLab::Dup(value, count) = &arg.label {
+            Ok((value, count.eval_word(assembler)?))
+        } else {
+            Ok((arg, 1))
+        }
+    }
+
+    /// Evaluate `.byte`-style arguments: strings are output directly, anything else as a single
+    /// byte.
+    fn byte_args(&self, assembler: &mut Assembler) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::with_capacity(self.args.len());
+        for arg in &self.args {
+            let (arg, count) = Self::dup_arg(arg, assembler)?;
+            for _ in 0..count {
+                if let Some(s) = Self::is_str_arg(arg) {
+                    bytes.extend(s.bytes());
+                } else {
+                    bytes.push(arg.eval_byte(assembler)?);
+                }
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Return the string argument for `.pstr`, checking its length.
+    fn pstr_arg(&self) -> Result<&str, String> {
+        if self.args.len() != 1 {
+            self.arg_count_err()
+        } else if let Some(s) = Self::is_str_arg(&self.args[0]) {
+            if s.len() > u8::MAX as usize {
+                self.line_slice()
+                    .err(&format!("string too long ({} > 255 bytes)", s.len()))
+            } else {
+                Ok(s)
+            }
+        } else {
+            self.line_slice().err("expected string argument")
+        }
+    }
+
+    /// Return the number of bytes from the PC up to the `.pad` address.
+    fn pad_size(&self, assembler: &mut Assembler) -> Result<u16, String> {
+        if self.args.is_empty() || self.args.len() > 2 {
+            return self.arg_count_err();
+        }
+        let addr = self.args[0].eval_word(assembler)?;
+        if assembler.pc() > addr {
+            self.line_slice().err(&format!(
+                "can't pad to {:04X}, already past it at {:04X}",
+                addr,
+                assembler.pc()
+            ))
+        } else {
+            Ok(addr - assembler.pc())
+        }
+    }
+
+    /// Split the `.checksum` arguments into the address range (either empty, or the start
+    /// and end) and the kind of checksum (`sum`, `xor`, or `neg`).
+    #[allow(clippy::vec_box)]
+    fn checksum_args(
+        &self,
+        assembler: &mut Assembler,
+    ) -> Result<(&[Box<ExprNode>], String), String> {
+        let mut args = self.args.as_slice();
+        let mut kind = "sum".to_string();
+        if let Some(last) = args.last() {
+            let name = last.slice.text().to_ascii_lowercase();
+            if last.label == ExLab::Name && matches!(name.as_str(), "sum" | "xor" | "neg") {
+                assembler.forget_ref(&last.slice);
+                kind = name;
+                args = &args[..args.len() - 1];
+            }
+        }
+        match args.len() {
+            0 | 2 => Ok((args, kind)),
+            _ => self.arg_count_err(),
+        }
+    }
+
+    /// Return the single keyword argument (such as `on`), in lowercase.
+    fn keyword_arg(&self, assembler: &mut Assembler) -> Result<String, String> {
+        if self.args.len() != 1 {
+            return self.arg_count_err();
+        }
+        let arg = &self.args[0];
+        if arg.label == ExLab::Name {
+            assembler.forget_ref(&arg.slice);
+            Ok(arg.slice.text().to_ascii_lowercase())
+        } else {
+            arg.slice.err("expected keyword")
+        }
+    }
+
+    /// Return the value of an `on`/`off` argument.
+    fn on_off_arg(&self, assembler: &mut Assembler) -> Result<bool, String> {
+        match self.keyword_arg(assembler)?.as_str() {
+            "on" => Ok(true),
+            "off" => Ok(false),
+            _ => self.args[0].slice.err("expected 'on' or 'off'"),
+        }
+    }
+
     /// If the expression tree is a string node, return that.
     fn is_str_arg(arg: &ExprNode) -> Option<&str> {
-        match &arg.label {
-            ExLab::Expr(e) => Self::is_str_arg(e),
-            ExLab::Str(s) => Some(s),
-            _ => None,
-        }
+        arg.str_literal()
     }
 }
 
@@ -58,13 +243,34 @@ impl Action for PseudoOp {
                 }
             }
             ".if" => {
-                if self.args.len() == 1 {
-                    let cond_val = self.args[0].eval(assembler)?;
-                    assembler.if_stack.push(cond_val != 0);
-                    Ok(0)
+                if self.args.len() != 1 {
+                    return self.arg_count_err();
+                }
+                // Inside a skipped branch, the whole chain is skipped unevaluated.
+                let active = if assembler.if_stack.last().is_some_and(|cond| !cond.active) {
+                    None
                 } else {
-                    self.arg_count_err()
+                    Some(self.args[0].eval(assembler)? != 0)
+                };
+                assembler.if_stack.push(Cond {
+                    active: active.unwrap_or(false),
+                    taken: active.unwrap_or(true),
+                    slice: self.line_slice(),
+                });
+                Ok(0)
+            }
+            ".elif" | ".elseif" => {
+                if self.args.len() != 1 {
+                    return self.arg_count_err();
                 }
+                let Some(taken) = assembler.if_stack.last().map(|cond| cond.taken) else {
+                    return self.line_slice().err("missing matching if");
+                };
+                let active = !taken && self.args[0].eval(assembler)? != 0;
+                let cond = assembler.if_stack.last_mut().unwrap();
+                cond.active = active;
+                cond.taken |= active;
+                Ok(0)
             }
             ".endif" => {
                 if assembler.if_stack.pop().is_none() {
@@ -76,8 +282,9 @@ impl Action for PseudoOp {
                 }
             }
             ".else" => {
-                if let Some(cond_val) = assembler.if_stack.last_mut() {
-                    *cond_val = !*cond_val;
+                if let Some(cond) = assembler.if_stack.last_mut() {
+                    cond.active = !cond.taken;
+                    cond.taken = true;
                     if self.args.is_empty() {
                         Ok(0)
                     } else {
@@ -88,23 +295,209 @@ impl Action for PseudoOp {
                 }
             }
             ".assert" => Ok(0),
-            ".dbg" => {
+            ".func" => {
+                let ExLab::Call(func, _) = &self.args[0].label else {
+                    unreachable!("'.func' is parsed into a call");
+                };
+                let name = &self.args[0].slice;
+                if assembler.pass == Pass::Pass1 {
+                    if assembler.funcs.contains_key(name.text()) {
+                        return name.err(&format!("function {} redefined", name.text()));
+                    }
+                    assembler
+                        .funcs
+                        .insert(name.text().to_string(), func.clone());
+                }
+                Ok(0)
+            }
+            ".limit" | ".maxpc" => {
+                if self.args.len() > 1 {
+                    return self.arg_count_err();
+                }
+                assembler.pc_limit = match self.args.first() {
+                    Some(arg) => Some(arg.eval_word(assembler)?),
+                    None => None,
+                };
+                Ok(0)
+            }
+            ".zpbase" => {
+                if self.args.len() != 1 {
+                    return self.arg_count_err();
+                }
+                let addr = self.args[0].eval_word(assembler)?;
+                if addr > 0xFF {
+                    return self.args[0]
+                        .slice
+                        .err(&format!("{:04X} isn't in zero page", addr));
+                }
+                assembler.zp_next = addr;
+                Ok(0)
+            }
+            ".zpvar" => {
+                if self.args.is_empty() || self.args.len() > 2 {
+                    return self.arg_count_err();
+                }
+                let name = &self.args[0];
+                if name.label != ExLab::Name {
+                    return name.slice.err("expected symbol name");
+                }
+                let size = match self.args.get(1) {
+                    Some(arg) => arg.eval_word(assembler)?,
+                    None => 1,
+                };
+                let addr = assembler.zp_next;
+                if addr as u32 + size as u32 > 0x100 {
+                    return self.line_slice().err(&format!(
+                        "zero page exhausted ({} bytes needed at {:02X})",
+                        size, addr
+                    ));
+                }
+                assembler.def_symbol(name.slice.text(), name.slice.clone(), addr)?;
+                assembler.zp_next = addr + size;
+                Ok(0)
+            }
+            ".echo" | ".out" => Ok(0),
+            ".cycles" => match self.args.len() {
+                2 => Ok(0),
+                _ => self.arg_count_err(),
+            },
+            ".set" | ".redef" => {
+                if self.args.len() != 2 {
+                    return self.arg_count_err();
+                }
+                let name = &self.args[0];
+                if name.label != ExLab::Name {
+                    return name.slice.err("expected symbol name");
+                }
+                if assembler.pass == Pass::Pass1 {
+                    // The name was parsed as a reference, but it's being defined.
+                    assembler.forget_ref(&name.slice);
+                }
+                let (value, negative) = self.args[1].eval_signed_word(assembler)?;
+                assembler.set_symbol(name.slice.text(), name.slice.clone(), value, negative)?;
+                Ok(0)
+            }
+            ".global" | ".export" => {
                 if self.args.is_empty() {
-                    assembler.debug_fmt = None;
+                    return self.arg_count_err();
+                }
+                for arg in &self.args {
+                    if arg.label != ExLab::Name {
+                        return arg.slice.err("expected symbol name");
+                    }
+                }
+                Ok(0)
+            }
+            ".checkpage" => {
+                if self.args.len() != 1 {
+                    self.arg_count_err()
+                } else {
                     Ok(0)
-                } else if self.args.len() != 1 {
+                }
+            }
+            ".page" => {
+                if !self.args.is_empty() {
                     self.arg_count_err()
-                } else if let Some(s) = Self::is_str_arg(&self.args[0]) {
-                    assembler.debug_fmt = Some(s.to_string());
+                } else {
+                    if assembler.page_start.take().is_none() {
+                        assembler.page_start = Some(assembler.pc());
+                    }
                     Ok(0)
+                }
+            }
+            ".title" | ".subttl" => {
+                let [arg] = &self.args[..] else {
+                    return self.arg_count_err();
+                };
+                let Some(text) = Self::is_str_arg(arg) else {
+                    return self.line_slice().err("expected string argument");
+                };
+                // The listing's built in pass1; pass2 only fills in the bytes.
+                if assembler.pass == Pass::Pass1 {
+                    if self.op_name_lcase == ".title" {
+                        assembler.list_title = Some(text.to_string());
+                        assembler.list_subtitle = None;
+                    } else {
+                        assembler.list_subtitle = Some(text.to_string());
+                    }
+                    assembler.list_heading(false);
+                }
+                Ok(0)
+            }
+            ".eject" => {
+                if !self.args.is_empty() {
+                    return self.arg_count_err();
+                }
+                if assembler.pass == Pass::Pass1 {
+                    assembler.list_heading(true);
+                }
+                Ok(0)
+            }
+            ".coldstart" | ".warmstart" => {
+                if self.args.len() > 1 {
+                    self.arg_count_err()
                 } else {
-                    self.line_slice().err("expected string argument")
+                    Ok(0)
+                }
+            }
+            ".dbg" => {
+                // An optional stream name comes first.
+                let mut args = self.args.as_slice();
+                let mut name = String::new();
+                if let Some(arg) = args.first() {
+                    if arg.label == ExLab::Name {
+                        assembler.forget_ref(&arg.slice);
+                        name = arg.slice.text().to_string();
+                        args = &args[1..];
+                    }
+                }
+                match args {
+                    [] => {
+                        assembler.debug_fmt.remove(&name);
+                        Ok(0)
+                    }
+                    [arg] => {
+                        if let Some(s) = Self::is_str_arg(arg) {
+                            assembler.debug_fmt.insert(name, s.to_string());
+                            Ok(0)
+                        } else {
+                            arg.slice.err("expected string argument")
+                        }
+                    }
+                    _ => self.arg_count_err(),
                 }
             }
             ".ds" => match self.args.len() {
-                1 | 2 => Ok(self.args[0].eval(assembler)?),
+                1 | 2 => Ok(self.args[0].eval_word(assembler)?),
                 _ => self.line_slice().err("Expected one or two args"),
             },
+            ".res" | ".space" => match self.args.len() {
+                1 => Ok(self.args[0].eval_word(assembler)?),
+                _ => self.arg_count_err(),
+            },
+            ".pad" | ".advance" => self.pad_size(assembler),
+            ".cpu" => {
+                if self.args.len() != 1 {
+                    self.arg_count_err()
+                } else if let Some(name) = Self::is_str_arg(&self.args[0]) {
+                    match Cpu::from_name(name) {
+                        Some(cpu) => {
+                            assembler.cpu = cpu;
+                            Ok(0)
+                        }
+                        None => self.args[0].slice.err(&format!(
+                            "unknown CPU '{}' (expected '6502' or '65c02'; the 65816 isn't supported)",
+                            name
+                        )),
+                    }
+                } else {
+                    self.line_slice().err("expected string argument")
+                }
+            }
+            ".checksum" => {
+                self.checksum_args(assembler)?;
+                Ok(1)
+            }
             ".bin" | ".incbin" => {
                 let bytes = self.pass2(assembler)?;
                 Ok(bytes.len() as u16)
@@ -112,7 +505,10 @@ impl Action for PseudoOp {
             ".inc" | ".lib" | ".fil" => {
                 for arg in &self.args {
                     if let Some(path) = Self::is_str_arg(arg) {
-                        match source::from_file(path) {
+                        let resolver = assembler.resolver.clone();
+                        let resolved =
+                            source::resolve_path(path, self.op_name.path(), resolver.as_ref());
+                        match source::from_resolver(&resolved, resolver.as_ref()) {
                             Ok(src) => assembler.src_stk.push(src),
                             Err(e) => {
                                 return self
@@ -126,13 +522,33 @@ impl Action for PseudoOp {
                 }
                 Ok(0)
             }
+            ".vectors" => {
+                if self.args.len() != 3 {
+                    return self.arg_count_err();
+                }
+                assembler.set_pc(VECTORS);
+                Ok(6)
+            }
+            ".once" => {
+                if !self.args.is_empty() {
+                    return self.arg_count_err();
+                }
+                if self.op_name.line().origin.is_some() {
+                    return self.line_slice().err("'.once' inside a macro");
+                }
+                let path = assembler.resolver.canonical(self.op_name.path());
+                if !assembler.once_files.insert(path) {
+                    assembler.src_stk.skip_rest();
+                }
+                Ok(0)
+            }
             "=" | ".equ" => {
                 if self.args.len() != 1 {
                     return self.arg_count_err();
                 }
                 if let Some(label) = label {
-                    let value = self.args[0].eval(assembler)?;
-                    assembler.def_symbol(label.clone().text(), label.clone(), value)?;
+                    let (value, negative) = self.args[0].eval_signed_word(assembler)?;
+                    assembler.def_equ(label.text(), label.clone(), value, negative)?;
                     Ok(0)
                 } else {
                     self.line_slice().err("missing label for '='")
@@ -140,27 +556,230 @@ impl Action for PseudoOp {
             }
             ".org" => {
                 if self.args.len() == 1 {
-                    let val = self.args[0].eval(assembler)?;
-                    assembler.pc = val;
+                    let val = self.args[0].eval_word(assembler)?;
+                    assembler.set_pc(val);
+                    assembler.pc_wrapped = false;
                     Ok(0)
                 } else {
                     self.arg_count_err()
                 }
             }
-            ".byte" => {
-                let mut sum = 0;
-                for arg in &self.args {
-                    if let Some(s) = Self::is_str_arg(arg) {
-                        sum += s.len() as u16;
-                    } else {
-                        sum += 1;
+            ".segment" => {
+                if self.args.len() != 1 {
+                    self.arg_count_err()
+                } else if let Some(name) = Self::is_str_arg(&self.args[0]) {
+                    if !assembler.pc_stack.is_empty() {
+                        return self.line_slice().err("'.segment' inside '.pushpc'");
                     }
+                    assembler.switch_section(name);
+                    Ok(0)
+                } else {
+                    self.line_slice().err("expected string argument")
+                }
+            }
+            ".pushpc" => {
+                if self.args.len() == 1 {
+                    let val = self.args[0].eval_word(assembler)?;
+                    assembler.pc_stack.push((assembler.pc(), val));
+                    assembler.set_pc(val);
+                    Ok(0)
+                } else {
+                    self.arg_count_err()
+                }
+            }
+            ".poppc" => {
+                if !self.args.is_empty() {
+                    self.arg_count_err()
+                } else if let Some((pc, logical)) = assembler.pc_stack.pop() {
+                    // Skip past the bytes output since the '.pushpc'.
+                    assembler.set_pc(pc.wrapping_add(assembler.pc().wrapping_sub(logical)));
+                    Ok(0)
+                } else {
+                    self.line_slice().err("'.poppc' without '.pushpc'")
+                }
+            }
+            ".pushorg" => {
+                if self.args.len() != 1 {
+                    return self.arg_count_err();
+                }
+                if !assembler.pc_stack.is_empty() {
+                    return self.line_slice().err("'.pushorg' inside '.pushpc'");
+                }
+                let val = self.args[0].eval_word(assembler)?;
+                assembler.push_org();
+                assembler.set_pc(val);
+                assembler.pc_wrapped = false;
+                Ok(0)
+            }
+            ".poporg" => {
+                if !self.args.is_empty() {
+                    self.arg_count_err()
+                } else if !assembler.pc_stack.is_empty() {
+                    self.line_slice().err("'.poporg' inside '.pushpc'")
+                } else if assembler.pop_org() {
+                    // Unlike '.poppc', the bytes since went elsewhere, so the PC is as it was.
+                    assembler.pc_wrapped = false;
+                    Ok(0)
+                } else {
+                    self.line_slice().err("'.poporg' without '.pushorg'")
+                }
+            }
+            ".struct" => {
+                if self.args.len() != 1 {
+                    return self.arg_count_err();
+                }
+                let name = &self.args[0];
+                if name.label != ExLab::Name {
+                    return name.slice.err("expected struct name");
+                }
+                if assembler.struct_def.is_some() {
+                    return self.line_slice().err("nested '.struct'");
+                }
+                assembler.struct_def = Some((name.slice.clone(), assembler.pc()));
+                assembler.set_pc(0);
+                Ok(0)
+            }
+            ".endstruct" => {
+                if !self.args.is_empty() {
+                    return self.arg_count_err();
+                }
+                let Some((name, pc)) = assembler.struct_def.take() else {
+                    return self.line_slice().err("'.endstruct' without '.struct'");
+                };
+                if assembler.pass == Pass::Pass1 {
+                    // The struct's name is its size.
+                    assembler.def_symbol(name.text(), name.clone(), assembler.pc())?;
+                }
+                assembler.set_pc(pc);
+                Ok(0)
+            }
+            ".scope" => {
+                if self.args.len() != 1 {
+                    return self.arg_count_err();
+                }
+                let name = &self.args[0];
+                if name.label != ExLab::Name || name.slice.text().contains(['.', ':']) {
+                    return name.slice.err("expected scope name");
+                }
+                assembler.forget_ref(&name.slice);
+                assembler.scopes.push(name.slice.text().to_string());
+                Ok(0)
+            }
+            ".endscope" => {
+                if !self.args.is_empty() {
+                    return self.arg_count_err();
+                }
+                if assembler.scopes.pop().is_none() {
+                    return self.line_slice().err("'.endscope' without '.scope'");
+                }
+                Ok(0)
+            }
+            ".enum" => {
+                if self.args.len() > 1 {
+                    return self.arg_count_err();
+                }
+                let start = match self.args.first() {
+                    Some(arg) => arg.eval_word(assembler)?,
+                    None => 0,
+                };
+                assembler.enum_next = Some(start);
+                Ok(0)
+            }
+            ".endenum" => {
+                if !self.args.is_empty() {
+                    self.arg_count_err()
+                } else if assembler.enum_next.take().is_none() {
+                    self.line_slice().err("'.endenum' without '.enum'")
+                } else {
+                    Ok(0)
                 }
-                Ok(sum)
             }
-            ".word" => Ok((self.args.len() * 2) as u16),
-            ".off" => Ok(0),
-            ".on" => Ok(0),
+            ".byte" if self.args.is_empty() && assembler.struct_def.is_some() => Ok(1),
+            ".word" if self.args.is_empty() && assembler.struct_def.is_some() => Ok(2),
+            ".byte.for" | ".word.for" => {
+                let count = self.for_range(assembler)?.count() as u32;
+                let size = if self.op_name_lcase == ".word.for" {
+                    count * 2
+                } else {
+                    count
+                };
+                u16::try_from(size).or_else(|_| {
+                    self.line_slice()
+                        .err(&format!("table of {size} bytes is too big"))
+                })
+            }
+            ".byte" | ".shift" | ".scrtext" => self.byte_args_size(assembler),
+            ".pstr" => Ok(self.pstr_arg()?.len() as u16 + 1),
+            ".word" => self.word_args_size(assembler),
+            ".evenwords" => {
+                assembler.even_words = self.on_off_arg(assembler)?;
+                Ok(0)
+            }
+            ".endian" => {
+                assembler.big_endian = match self.keyword_arg(assembler)?.as_str() {
+                    "big" => true,
+                    "little" => false,
+                    _ => return self.args[0].slice.err("expected 'big' or 'little'"),
+                };
+                Ok(0)
+            }
+            ".signed" | ".unsigned" => {
+                if self.args.is_empty() {
+                    assembler.signed = self.op_name_lcase == ".signed";
+                    Ok(0)
+                } else {
+                    self.arg_count_err()
+                }
+            }
+            ".autobranch" => {
+                assembler.auto_branch = self.on_off_arg(assembler)?;
+                Ok(0)
+            }
+            ".warnpage" => {
+                assembler.warn_page = self.on_off_arg(assembler)?;
+                Ok(0)
+            }
+            ".docparas" => {
+                assembler.doc_paras = self.on_off_arg(assembler)?;
+                Ok(0)
+            }
+            ".longbranch" => {
+                assembler.long_branches = self.on_off_arg(assembler)?;
+                Ok(0)
+            }
+            ".aliases" => {
+                assembler.aliases = self.on_off_arg(assembler)?;
+                Ok(0)
+            }
+            ".warn-jmpbug" | ".warnjmpbug" => {
+                assembler.warn_jmp_bug = self.on_off_arg(assembler)?;
+                Ok(0)
+            }
+            ".warnonce" => {
+                assembler.warn_once = self.on_off_arg(assembler)?;
+                Ok(0)
+            }
+            ".dbgoutput" => {
+                assembler.dbg_output_only = self.on_off_arg(assembler)?;
+                Ok(0)
+            }
+            ".zpopt" => {
+                assembler.zp_opt = self.on_off_arg(assembler)?;
+                Ok(0)
+            }
+            ".off" => {
+                // Set in both passes, since pass1's debug output can depend on it.
+                assembler.output_flag = false;
+                Ok(0)
+            }
+            ".on" => {
+                assembler.output_flag = true;
+                Ok(0)
+            }
+            name if name.starts_with(".warn-") => self.op_name.err(&format!(
+                "unknown warning '{}' (expected '.warn-jmpbug')",
+                self.op_name.text()
+            )),
             _ => self
                 .line_slice()
                 .err(&format!("bad pseudo-op '{}'", self.op_name.text())),
@@ -170,7 +789,7 @@ impl Action for PseudoOp {
     fn pass2(&self, assembler: &mut Assembler) -> Result<Vec<u8>, String> {
         match self.op_name_lcase.as_str() {
             ".mac" => Ok(Vec::new()),
-            ".if" | ".else" | ".endif" => {
+            ".if" | ".elif" | ".elseif" | ".else" | ".endif" => {
                 // statements skipped by these should already have been deleted, so do nothing.
                 Ok(vec![])
             }
@@ -186,27 +805,142 @@ impl Action for PseudoOp {
                     self.arg_count_err()
                 }
             }
-            ".on" => {
-                assembler.output_flag = true;
+            ".echo" | ".out" => {
+                let mut msg = String::new();
+                for arg in self.args.iter() {
+                    if let Some(s) = Self::is_str_arg(arg) {
+                        msg.push_str(s);
+                    } else {
+                        msg.push_str(&format!("${:04X}", arg.eval(assembler)?));
+                    }
+                }
+                if assembler.pass == Pass::Pass2 {
+                    eprintln!("{}", msg);
+                }
                 Ok(vec![])
             }
-            ".off" => {
-                assembler.output_flag = false;
+            ".cycles" => {
+                // Reported at the end of pass2, once every instruction's been assembled.
+                let start = self.args[0].eval_word(assembler)?;
+                let end = self.args[1].eval_word(assembler)?;
+                if start > end {
+                    return self.line_slice().err(&format!(
+                        "cycle count start {start:04X} is past end {end:04X}"
+                    ));
+                }
+                assembler.cycle_spans.push((start, end, self.line_slice()));
+                Ok(vec![])
+            }
+            ".global" | ".export" => {
+                // Marked in the second pass, so the symbols can be defined after this.
+                for arg in &self.args {
+                    assembler.export(&arg.slice)?;
+                }
+                Ok(vec![])
+            }
+            ".checkpage" => {
+                if self.args.len() != 1 {
+                    return self.arg_count_err();
+                }
+                let start = self.args[0].eval_word(assembler)?;
+                let pc = assembler.pc();
+                if start > pc {
+                    return self
+                        .line_slice()
+                        .err(&format!("page check start {start:04X} is past PC {pc:04X}"));
+                }
+                if start != pc && (start >> 8) != (pc - 1) >> 8 {
+                    assembler.warn(
+                        &self.line_slice(),
+                        &format!("{:04X}-{:04X} crosses a page boundary", start, pc - 1),
+                    );
+                }
+                Ok(vec![])
+            }
+            ".page" => {
+                if !self.args.is_empty() {
+                    return self.arg_count_err();
+                }
+                if let Some(start) = assembler.page_start.take() {
+                    let pc = assembler.pc();
+                    if start != pc && (start >> 8) != pc.wrapping_sub(1) >> 8 {
+                        return self.line_slice().err(&format!(
+                            "{:04X}-{:04X} crosses a page boundary",
+                            start,
+                            pc.wrapping_sub(1)
+                        ));
+                    }
+                } else {
+                    assembler.page_start = Some(assembler.pc());
+                }
+                Ok(vec![])
+            }
+            ".coldstart" | ".warmstart" => {
+                let addr = if let Some(arg) = self.args.first() {
+                    arg.eval_word(assembler)?
+                } else {
+                    assembler.pc()
+                };
+                let entry = if self.op_name_lcase == ".coldstart" {
+                    &mut assembler.cold_start
+                } else {
+                    &mut assembler.warm_start
+                };
+                if entry.is_some() {
+                    self.line_slice().err("entry point redefined")
+                } else {
+                    *entry = Some(addr);
+                    Ok(vec![])
+                }
+            }
+            ".res" | ".space" => {
+                // The PC moves past the space, but nothing is output for it.
+                let size = self.args[0].eval_word(assembler)?;
+                assembler.set_pc(assembler.pc().wrapping_add(size));
                 Ok(vec![])
             }
             ".ds" => match self.args.len() {
-                1 => Ok(vec![0; self.args[0].eval(assembler)? as usize]),
+                1 => Ok(vec![0; self.args[0].eval_word(assembler)? as usize]),
                 2 => Ok(vec![
-                    self.args[1].eval(assembler)?.to_le_bytes()[0];
-                    self.args[0].eval(assembler)? as usize
+                    self.args[1].eval_byte(assembler)?;
+                    self.args[0].eval_word(assembler)? as usize
                 ]),
                 _ => panic!(),
             },
+            ".checksum" => {
+                let (range, kind) = self.checksum_args(assembler)?;
+                let (start, end) = match range {
+                    [start, end] => (
+                        start.eval_word(assembler)? as u32,
+                        end.eval_word(assembler)? as u32,
+                    ),
+                    _ => (0, 0x10000),
+                };
+                let bytes = assembler.output_bytes(start, end);
+                let sum = bytes.iter().fold(0u8, |sum, b| match kind.as_str() {
+                    "xor" => sum ^ b,
+                    _ => sum.wrapping_add(*b),
+                });
+                if kind == "neg" {
+                    Ok(vec![sum.wrapping_neg()])
+                } else {
+                    Ok(vec![sum])
+                }
+            }
+            ".pad" | ".advance" => {
+                let size = self.pad_size(assembler)? as usize;
+                match self.args.get(1) {
+                    Some(fill) => Ok(vec![fill.eval_byte(assembler)?; size]),
+                    None => Ok(vec![0; size]),
+                }
+            }
             ".bin" | ".incbin" => {
                 if self.args.len() != 1 {
                     self.arg_count_err()
                 } else if let Some(path) = Self::is_str_arg(&self.args[0]) {
-                    match fs::read(path) {
+                    let resolver = assembler.resolver.as_ref();
+                    match resolver.read(&source::resolve_path(path, self.op_name.path(), resolver))
+                    {
                         Ok(bytes) => Ok(bytes),
                         Err(e) => self
                             .line_slice()
@@ -216,24 +950,66 @@ impl Action for PseudoOp {
                     self.line_slice().err("expected filename")
                 }
             }
-            ".inc" | ".lib" | ".fil" => Ok(vec![]),
+            ".inc" | ".lib" | ".fil" | ".once" => Ok(vec![]),
+            ".vectors" => {
+                // Always little endian, as the CPU reads them.
+                assembler.set_pc(VECTORS);
+                let mut bytes = Vec::with_capacity(6);
+                for arg in &self.args {
+                    bytes.extend(arg.eval_word(assembler)?.to_le_bytes());
+                }
+                Ok(bytes)
+            }
             "=" | ".equ" => Ok(vec![]),
             ".org" => self.pass1(assembler, None).map(|_| vec![]),
-            ".byte" => {
-                let mut bytes = Vec::with_capacity(self.args.len());
-                for arg in &self.args {
-                    if let Some(s) = Self::is_str_arg(arg) {
-                        bytes.extend(s.bytes());
-                    } else {
-                        bytes.push(arg.eval(assembler)?.to_le_bytes()[0]);
-                    }
+            // The symbols were already defined in pass1.
+            ".zpvar" | ".zpbase" | ".enum" => Ok(vec![]),
+            // Only checked in pass1.
+            ".limit" | ".maxpc" => Ok(vec![]),
+            // A struct member's space isn't output, but must be counted.
+            ".byte" if self.args.is_empty() && assembler.struct_def.is_some() => Ok(vec![0]),
+            ".word" if self.args.is_empty() && assembler.struct_def.is_some() => Ok(vec![0; 2]),
+            ".byte" => self.byte_args(assembler),
+            ".shift" | ".scrtext" => {
+                let mut bytes = self.byte_args(assembler)?;
+                if let Some(last) = bytes.last_mut() {
+                    *last |= 0x80;
                 }
                 Ok(bytes)
             }
+            ".pstr" => {
+                let s = self.pstr_arg()?;
+                let mut bytes = Vec::with_capacity(s.len() + 1);
+                bytes.push(s.len() as u8);
+                bytes.extend(s.bytes());
+                Ok(bytes)
+            }
             ".word" => {
                 let mut bytes = Vec::with_capacity(self.args.len() * 2);
                 for arg in &self.args {
-                    bytes.extend(arg.eval(assembler)?.to_le_bytes());
+                    let (arg, count) = Self::dup_arg(arg, assembler)?;
+                    let word = arg.eval_word(assembler)?;
+                    let word_bytes = if assembler.big_endian {
+                        word.to_be_bytes()
+                    } else {
+                        word.to_le_bytes()
+                    };
+                    for _ in 0..count {
+                        bytes.extend(word_bytes);
+                    }
+                }
+                Ok(bytes)
+            }
+            ".byte.for" => self.for_values(assembler, ExprNode::eval_byte),
+            ".word.for" => {
+                let words = self.for_values(assembler, ExprNode::eval_word)?;
+                let mut bytes = Vec::with_capacity(words.len() * 2);
+                for word in words {
+                    if assembler.big_endian {
+                        bytes.extend(word.to_be_bytes());
+                    } else {
+                        bytes.extend(word.to_le_bytes());
+                    }
                 }
                 Ok(bytes)
             }
@@ -254,22 +1030,45 @@ impl Action for PseudoOp {
     }
 
     fn is_if_affiliated(&self) -> bool {
-        matches!(self.op_name_lcase.as_str(), ".else" | ".endif")
+        matches!(
+            self.op_name_lcase.as_str(),
+            ".if" | ".elif" | ".elseif" | ".else" | ".endif"
+        )
+    }
+
+    fn is_enum_affiliated(&self) -> bool {
+        self.op_name_lcase == ".endenum"
     }
 
-    fn is_macro_def(&self) -> Option<String> {
+    fn is_struct_affiliated(&self) -> bool {
+        match self.op_name_lcase.as_str() {
+            ".byte" | ".word" => self.args.is_empty(),
+            ".ds" | ".res" | ".space" | "=" | ".equ" | ".struct" | ".endstruct" | ".endm" => true,
+            _ => self.is_if_affiliated(),
+        }
+    }
+
+    fn is_macro_def(&self) -> Option<Rc<LineSlice>> {
         if self.op_name_lcase.as_str() == ".mac" && self.args.len() == 1 {
             let arg = &self.args[0];
             if arg.label == ExLab::Name {
-                return Some(arg.slice.text().to_string());
+                return Some(arg.slice.clone());
             }
         }
         None
     }
 
+    fn align_pad(&self, assembler: &mut Assembler) -> u16 {
+        if self.op_name_lcase == ".word" && assembler.even_words {
+            assembler.pc() & 1
+        } else {
+            0
+        }
+    }
+
     fn eval_equ(&self, assembler: &mut Assembler) -> Option<u16> {
         if self.is_equ() {
-            self.args.first()?.eval(assembler).ok()
+            self.args.first()?.eval_word(assembler).ok()
         } else {
             None
         }
@@ -278,7 +1077,13 @@ impl Action for PseudoOp {
 
 #[cfg(test)]
 mod tests {
-    use crate::{assemble, assemble_str, source};
+    use std::{collections::HashMap, fs, rc::Rc};
+
+    use crate::{
+        assemble, assemble_str, assemble_with, assemble_with_diagnostics,
+        source::{self, MemFiles},
+        AsmOptions,
+    };
 
     #[test]
     fn test_onoff() {
@@ -295,6 +1100,33 @@ foobar";
         assert!(info.bytes.is_empty());
     }
 
+    #[test]
+    fn test_title() {
+        let src = "        .title \"Demo\"
+        .subttl \"Part 1\"
+        lda #1
+        .eject
+        rts";
+        let info = assemble(source::from_str(src, "title.s"), true).unwrap();
+        assert_eq!(info.bytes, vec![0xA9, 0x01, 0x60]);
+        assert_eq!(
+            info.listing.unwrap(),
+            "LINENO PC   BYTES  LINE
+Demo
+000001 0000                .title \"Demo\"
+Demo - Part 1
+000002 0000                .subttl \"Part 1\"
+000003 0000 A901           lda #1
+\x0CDemo - Part 1
+000004 0002                .eject
+000005 0002 60             rts
+
+SYMBOLS
+"
+        );
+        assert!(assemble_str(".title 1", "title.s").is_err());
+    }
+
     #[test]
     fn test_assert() {
         assert!(assemble_str(
@@ -320,4 +1152,662 @@ BAR = 2
 .ENDIF";
         assert_eq!(assemble_str(src, "src"), Ok(vec![4, 5, 6, 7, 8, 9]));
     }
+
+    #[test]
+    fn test_elif() {
+        let chain = "
+        .if MODE = 1
+        .byte 1
+        .elif MODE = 2
+        .byte 2
+        .elseif MODE >= 2
+        .byte 3
+        .else
+        .byte 4
+        .endif";
+        for (mode, byte) in [(1, 1), (2, 2), (3, 3), (0, 4)] {
+            let src = format!("MODE = {}{}", mode, chain);
+            assert_eq!(assemble_str(&src, "elif.s"), Ok(vec![byte]));
+        }
+
+        // A chain inside a skipped branch is skipped, along with its `.endif`.
+        let src = "
+        .if 0
+        .if 1
+        .byte 1
+        .elif undefined
+        .byte 2
+        .endif
+        .byte 3
+        .endif
+        .byte 4";
+        assert_eq!(assemble_str(src, "elif.s"), Ok(vec![4]));
+        assert!(assemble_str(".elif 1", "elif.s").is_err());
+    }
+
+    #[test]
+    fn test_pushpc() {
+        let src = "        .org $8000
+        lda #1
+        .pushpc $C000
+reloc   jmp reloc
+        .byte 2
+        .poppc
+after   .word reloc,after";
+        let info = assemble(source::from_str(src, "pushpc.s"), false).unwrap();
+        assert_eq!(info.symtab["reloc"].value, Some(0xC000));
+        assert_eq!(info.symtab["after"].value, Some(0x8006));
+        assert_eq!(
+            info.bytes,
+            vec![0xA9, 1, 0x4C, 0x00, 0xC0, 2, 0x00, 0xC0, 0x06, 0x80]
+        );
+        assert!(assemble(source::from_str(".poppc", "pushpc.s"), false).is_err());
+        assert!(assemble(source::from_str(".pushpc $C000", "pushpc.s"), false).is_err());
+    }
+
+    #[test]
+    fn test_pushorg() {
+        let src = "        .org $8000
+        lda #1
+        .pushorg $C000
+vector  .word after
+        .pushorg $FFFA
+        .word vector
+        .poporg
+        .poporg
+after   rts";
+        let info = assemble(source::from_str(src, "pushorg.s"), false).unwrap();
+        assert_eq!(info.symtab["vector"].value, Some(0xC000));
+        assert_eq!(info.symtab["after"].value, Some(0x8002));
+        assert_eq!(
+            info.segments,
+            vec![
+                (0x8000, vec![0xA9, 1]),
+                (0xC000, vec![0x02, 0x80]),
+                (0xFFFA, vec![0x00, 0xC0]),
+                (0x8002, vec![0x60]),
+            ]
+        );
+        // The segment is restored too.
+        let src = "        .segment \"CODE\"
+        .org $8000
+        nop
+        .pushorg $C000
+        .segment \"DATA\"
+        .org $0200
+buffer  .byte 1
+        .poporg
+        rts
+        .segment \"DATA\"
+        .byte 2";
+        let info = assemble(source::from_str(src, "pushorg.s"), false).unwrap();
+        assert_eq!(info.symtab["buffer"].value, Some(0x0200));
+        assert_eq!(
+            info.segments,
+            vec![(0x8000, vec![0xEA, 0x60]), (0x0200, vec![1, 2])]
+        );
+        assert!(assemble_str(".poporg", "pushorg.s").is_err());
+        assert!(assemble_str(".pushorg $C000", "pushorg.s").is_err());
+        assert!(assemble_str(".pushpc $C000\n.pushorg $D000", "pushorg.s").is_err());
+    }
+
+    #[test]
+    fn test_page() {
+        let table = "        .org $80F0
+        .page
+table   .ds 20
+        .page";
+        assert!(assemble_str(table, "page.s").is_err());
+        let table = "        .org $80E0
+        .page
+table   .ds 20
+        .page
+        .ds 20";
+        assert_eq!(assemble_str(table, "page.s").unwrap(), vec![0; 40]);
+        assert!(assemble_str(".page", "page.s").is_err());
+    }
+
+    #[test]
+    fn test_echo() {
+        let src = "START   nop
+        .echo \"code size: \", * - START, ' bytes'
+        .out END
+END     nop";
+        assert_eq!(assemble_str(src, "echo.s"), Ok(vec![0xEA, 0xEA]));
+        assert!(assemble_str(".echo 1/0", "echo.s").is_err());
+    }
+
+    #[test]
+    fn test_segment() {
+        let src = "        .org $8000
+        .segment \"DATA\"
+        .org $9000
+msg     .byte 1, 2
+        .segment \"CODE\"
+        .org $C000
+start   lda msg
+        .segment \"DATA\"
+msg2    .byte 3
+        .segment \"CODE\"
+        jmp start";
+        let info = assemble(source::from_str(src, "seg.s"), false).unwrap();
+        assert_eq!(info.symtab["msg2"].value, Some(0x9002));
+        assert_eq!(
+            info.bytes,
+            vec![1, 2, 3, 0xAD, 0x00, 0x90, 0x4C, 0x00, 0xC0]
+        );
+        assert_eq!(
+            info.segments,
+            vec![
+                (0x9000, vec![1, 2, 3]),
+                (0xC000, vec![0xAD, 0x00, 0x90, 0x4C, 0x00, 0xC0])
+            ]
+        );
+        assert!(assemble_str(".segment 1", "seg.s").is_err());
+    }
+
+    #[test]
+    fn test_zpvar() {
+        let src = "        .zpbase $FB
+        .zpvar ptr1, 2
+        .zpvar ptr2, 2
+        .zpvar count
+        lda (ptr2),y
+        sta count";
+        let info = assemble(source::from_str(src, "zp.s"), false).unwrap();
+        assert_eq!(info.symtab["ptr1"].value, Some(0xFB));
+        assert_eq!(info.symtab["ptr2"].value, Some(0xFD));
+        assert_eq!(info.symtab["count"].value, Some(0xFF));
+        assert_eq!(info.bytes, vec![0xB1, 0xFD, 0x85, 0xFF]);
+
+        assert!(assemble_str(".zpbase $FF\n.zpvar ptr, 2", "zp.s").is_err());
+        assert!(assemble_str(".zpbase $100", "zp.s").is_err());
+        assert!(assemble_str(".zpvar ptr\nptr nop", "zp.s").is_err());
+    }
+
+    #[test]
+    fn test_struct() {
+        let src = "        .org $1000
+        .struct point
+flags   .byte
+xpos    .word
+ypos    .res 2
+        .endstruct
+origin  .ds point
+        lda origin+point::xpos
+        ldy #point::ypos";
+        let info = assemble(source::from_str(src, "struct.s"), false).unwrap();
+        assert_eq!(info.symtab["point::flags"].value, Some(0));
+        assert_eq!(info.symtab["point::xpos"].value, Some(1));
+        assert_eq!(info.symtab["point::ypos"].value, Some(3));
+        assert_eq!(info.symtab["point"].value, Some(5));
+        assert_eq!(info.symtab["origin"].value, Some(0x1000));
+        assert_eq!(info.bytes, vec![0, 0, 0, 0, 0, 0xAD, 0x01, 0x10, 0xA0, 3]);
+
+        assert!(assemble_str(".struct foo\n.byte", "struct.s").is_err());
+        assert!(assemble_str(".endstruct", "struct.s").is_err());
+        assert!(assemble_str(".struct a\n.struct b\n.endstruct\n.endstruct", "struct.s").is_err());
+
+        // Anything that would output bytes, or do anything but lay out the struct, is an error.
+        let src = "        .mac field
+\\1      .word
+        .endm
+        .struct point
+        .if 1
+SIZE    = 4
+        .endif
+        field xpos
+        .ds SIZE
+        .endstruct";
+        let info = assemble(source::from_str(src, "struct.s"), false).unwrap();
+        assert_eq!(info.symtab["point::xpos"].value, Some(0));
+        assert_eq!(info.symtab["point"].value, Some(6));
+        for member in [
+            "lda #1",
+            ".byte 1",
+            ".word 2",
+            ".org $1000",
+            ".pad 4",
+            ".incbin \"x\"",
+        ] {
+            let src = format!("        .struct foo\n        {member}\n        .endstruct");
+            let info = assemble_with_diagnostics(source::from_str(&src, "struct.s"), false);
+            assert!(info.diagnostics[0]
+                .message
+                .starts_with("struct.s:2:9: only labels, '=', '.byte'/'.word' without values"));
+        }
+    }
+
+    #[test]
+    fn test_enum() {
+        let src = "        .enum
+IDLE
+RUN:
+STOP    ; stops
+        .endenum
+        .enum 10
+A
+B       = 5
+C
+        .endenum
+        .byte IDLE, RUN, STOP, A, B, C";
+        assert_eq!(assemble_str(src, "enum.s"), Ok(vec![0, 1, 2, 10, 5, 6]));
+        assert!(assemble_str(".enum\nFOO nop\n.endenum", "enum.s").is_err());
+        assert!(assemble_str(".enum\n.byte 1\n.endenum", "enum.s").is_err());
+        assert!(assemble_str(".enum\nFOO", "enum.s").is_err());
+        assert!(assemble_str(".endenum", "enum.s").is_err());
+    }
+
+    #[test]
+    fn test_byte_range() {
+        let err = assemble_str("        .byte 1, $1234", "byte.s").unwrap_err();
+        assert_eq!(err, "1 errors in pass 2");
+        let info = assemble_with_diagnostics(source::from_str(".byte 1, $1234", "byte.s"), false);
+        assert!(info.diagnostics[0]
+            .message
+            .starts_with("byte.s:1:10: value $1234 doesn't fit"));
+        assert_eq!(
+            assemble_str(".byte <$1234, >$1234, -128, 255", "byte.s"),
+            Ok(vec![0x34, 0x12, 0x80, 0xFF])
+        );
+        assert!(assemble_str(".byte -129", "byte.s").is_err());
+        assert_eq!(
+            assemble_str(".byte -1, -128", "byte.s"),
+            Ok(vec![0xFF, 0x80])
+        );
+        let info = assemble_with_diagnostics(source::from_str(".byte -200", "byte.s"), false);
+        assert!(info.diagnostics[0]
+            .message
+            .starts_with("byte.s:1:7: value -200 doesn't fit in a byte"));
+
+        // A symbol defined as a negative number is checked as that number, not its word.
+        let src = "
+MINUS_ONE = -1
+        .set LOW, -128
+        .byte MINUS_ONE, LOW, MINUS_ONE + 2
+        .word MINUS_ONE
+        lda #MINUS_ONE
+        ldx MINUS_ONE";
+        assert_eq!(
+            assemble_str(src, "byte.s"),
+            Ok(vec![
+                0xFF, 0x80, 1, 0xFF, 0xFF, 0xA9, 0xFF, 0xAE, 0xFF, 0xFF
+            ])
+        );
+        let info = assemble_with_diagnostics(
+            source::from_str("TOO_LOW = -200\n.byte TOO_LOW", "byte.s"),
+            false,
+        );
+        assert!(info.diagnostics[0]
+            .message
+            .starts_with("byte.s:2:7: value -200 doesn't fit in a byte"));
+        assert!(assemble_str("ADDR = $FFFF\n.byte ADDR", "byte.s").is_err());
+        assert!(assemble_str("ADDR = $FFFF\nlda #ADDR", "byte.s").is_err());
+    }
+
+    #[test]
+    fn test_endian() {
+        let src = "
+        .word $1234
+        .endian big
+        .word $1234, $56 dup 2
+        lda $1234
+        .endian little
+        .word $1234";
+        assert_eq!(
+            assemble_str(src, "endian.s"),
+            Ok(vec![
+                0x34, 0x12, 0x12, 0x34, 0x00, 0x56, 0x00, 0x56, 0xAD, 0x34, 0x12, 0x34, 0x12
+            ])
+        );
+        assert!(assemble_str(".endian middle", "endian.s").is_err());
+    }
+
+    #[test]
+    fn test_checksum() {
+        let src = "
+        .org $8000
+start   .byte $10, $20, $F0, $01
+end     .checksum
+        .checksum start, end, xor
+        .checksum start, start+2, NEG
+        .checksum start, end, sum
+        .checksum later, later+1
+later   .byte 5";
+        assert_eq!(
+            assemble_str(src, "sum.s"),
+            Ok(vec![0x10, 0x20, 0xF0, 0x01, 0x21, 0xC1, 0xD0, 0x21, 0, 5])
+        );
+        assert!(assemble_str(".checksum 1", "sum.s").is_err());
+        assert!(assemble_str(".checksum 1, 2, 3", "sum.s").is_err());
+    }
+
+    #[test]
+    fn test_pad() {
+        let src = "
+        .byte 1,2,3,4
+        .pad $10
+end     .byte FILL
+        .advance $14, FILL
+        .pad $14
+FILL    = $EA";
+        let info = assemble(source::from_str(src, "pad.s"), false).unwrap();
+        let mut bytes = vec![1, 2, 3, 4];
+        bytes.extend([0; 12]);
+        bytes.extend([0xEA; 4]);
+        assert_eq!(info.bytes, bytes);
+        assert_eq!(info.symtab["end"].value, Some(0x10));
+        assert!(assemble_str(".org 5\n.pad 4", "pad.s").is_err());
+        assert!(assemble_str(".pad later\nlater = 4", "pad.s").is_err());
+    }
+
+    #[test]
+    fn test_star_org() {
+        let src = "
+        *= $c000
+start   nop
+  * = start + $10
+end     .word start";
+        let info = assemble(source::from_str(src, "org.s"), false).unwrap();
+        assert_eq!(info.symtab["start"].value, Some(0xC000));
+        assert_eq!(info.symtab["end"].value, Some(0xC010));
+        assert_eq!(
+            info.segments,
+            vec![(0xC000, vec![0xEA]), (0xC010, vec![0, 0xC0])]
+        );
+        assert_eq!(
+            assemble_str("*=$10\n.byte *, * * 2", "org.s"),
+            Ok(vec![0x10, 0x20])
+        );
+    }
+
+    #[test]
+    fn test_dup() {
+        assert_eq!(
+            assemble_str(".byte $FF dup 3, 1", "dup.s"),
+            Ok(vec![255, 255, 255, 1])
+        );
+        let src = "
+COUNT   = 2
+        .word $1234 DUP COUNT, end
+        .byte 'ab' dup 2, 0 dup 0
+        .shift 1 dup 2
+end";
+        assert_eq!(
+            assemble_str(src, "dup.s"),
+            Ok(vec![
+                0x34, 0x12, 0x34, 0x12, 12, 0, b'a', b'b', b'a', b'b', 1, 0x81
+            ])
+        );
+        assert!(assemble_str(".byte 0 dup later\nlater = 2", "dup.s").is_err());
+        assert!(assemble_str("foo = 1 dup 2", "dup.s").is_err());
+        assert!(assemble_str("lda #1 dup 2", "dup.s").is_err());
+        for src in [
+            ".byte 'abc' dup $6000",
+            ".word 0 dup $8000",
+            ".byte 0 dup $FFFF, 0",
+        ] {
+            let info = assemble_with_diagnostics(source::from_str(src, "dup.s"), false);
+            assert!(info.diagnostics[0].message.contains("too many bytes"));
+        }
+    }
+
+    #[test]
+    fn test_if_strings() {
+        let src = "
+        .if \"a\" == \"a\"
+        .byte 1
+        .endif
+        .if \"a\" == \"b\"
+        .byte 2
+        .endif
+        .if 'c64' <> \"vic20\"
+        .byte 3
+        .endif
+        .if ('c64' = \"c64\") + ('x' = 'y')
+        .byte 4
+        .endif";
+        assert_eq!(assemble_str(src, "if.s"), Ok(vec![1, 3, 4]));
+        assert!(assemble_str(".if 'ab' < 'b'\n.endif", "if.s").is_err());
+        assert!(assemble_str(".if 'ab' = 1\n.endif", "if.s").is_err());
+    }
+
+    #[test]
+    fn test_pc_wrap() {
+        assert!(assemble_str(".org $FFFE\nnop\nnop\nnop", "wrap.s").is_err());
+        assert!(assemble_str(".org $FFFE\nlda $1234", "wrap.s").is_err());
+        assert_eq!(
+            assemble_str(".org $FFFE\n.word $1234\n.org 0\nnop", "wrap.s"),
+            Ok(vec![0x34, 0x12, 0xEA])
+        );
+        assert_eq!(
+            assemble_str(".org $FFFF\nnop\nFOO = 1\n.org $1000\nnop", "wrap.s"),
+            Ok(vec![0xEA, 0xEA])
+        );
+    }
+
+    #[test]
+    fn test_limit() {
+        let src = "        .org $9FFD
+        .limit $A000
+        lda $1234
+        .limit
+        nop";
+        assert_eq!(
+            assemble_str(src, "limit.s"),
+            Ok(vec![0xAD, 0x34, 0x12, 0xEA])
+        );
+        let src = "        .org $9FFE
+        .maxpc $A000
+        lda $1234";
+        assert!(assemble_str(src, "limit.s").is_err());
+    }
+
+    #[test]
+    fn test_evenwords() {
+        let src = "
+        .org $1001
+        .evenwords on
+foo     .word $1234
+bar     .word bar
+        .byte 1
+        .evenwords off
+foobar  .word $5678";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(info.bytes, vec![0, 0x34, 0x12, 0x04, 0x10, 1, 0x78, 0x56]);
+        assert_eq!(info.symtab["foo"].value, Some(0x1002));
+        assert_eq!(info.symtab["bar"].value, Some(0x1004));
+        assert_eq!(info.symtab["foobar"].value, Some(0x1007));
+        assert!(!info.symtab.contains_key("on"));
+        assert!(assemble_str(".evenwords maybe", "src").is_err());
+    }
+
+    #[test]
+    fn test_for() {
+        assert_eq!(
+            assemble_str(".byte.for i, 0, 3 : i", "for.s"),
+            Ok(vec![0, 1, 2, 3])
+        );
+        let src = "        .func square(n) = n * n
+table   .word.for i, 2, 4 : square(i) + end
+        .byte.for I, 1, 2 : -I
+end";
+        let info = assemble(source::from_str(src, "for.s"), false).unwrap();
+        assert_eq!(info.bytes, vec![12, 0, 17, 0, 24, 0, 0xFF, 0xFE]);
+        assert!(assemble_str(".byte.for i, 0, 256 : i * 2", "for.s").is_err());
+        assert!(assemble_str(".byte.for i, 3, 0 : i", "for.s").is_err());
+        assert!(assemble_str(".byte.for i, 0, last : i\nlast = 3", "for.s").is_err());
+    }
+
+    #[test]
+    fn test_vectors() {
+        let src = "        .org $e000
+reset   sei
+nmi     rti
+irq     rti
+        .endian big
+        .vectors nmi, reset, irq";
+        let info = assemble(source::from_str(src, "vec.s"), true).unwrap();
+        assert_eq!(
+            info.segments,
+            vec![
+                (0xE000, vec![0x78, 0x40, 0x40]),
+                (0xFFFA, vec![0x01, 0xE0, 0x00, 0xE0, 0x02, 0xE0])
+            ]
+        );
+        assert!(info.listing.unwrap().contains("000006 FFFA 01E000"));
+        assert!(assemble_str(".vectors 1, 2", "vec.s").is_err());
+    }
+
+    #[test]
+    fn test_once() {
+        let once = |lib: &'static str| {
+            let options = AsmOptions {
+                resolver: Rc::new(MemFiles(HashMap::from([("lib/lib.s", lib.as_bytes())]))),
+                ..AsmOptions::default()
+            };
+            // The same file, named two ways.
+            let src = "        .inc \"lib/lib.s\"
+        .inc \"./lib/./lib.s\"
+        .byte LIB_VALUE
+        jsr libfn";
+            assemble_with(source::from_str(src, "once.s"), &options)
+        };
+        let info = once("        .once\nLIB_VALUE = 7\nlibfn   rts\n").unwrap();
+        assert_eq!(info.bytes, vec![0x60, 7, 0x20, 0, 0]);
+        // Without it, the second include redefines the symbols.
+        assert!(once("LIB_VALUE = 7\nlibfn   rts\n").is_err());
+        assert!(assemble_str(".mac m\n.once\n.endm\nm", "once.s").is_err());
+    }
+
+    #[test]
+    fn test_filesize() {
+        fs::write("size.bin", [1, 2, 3, 4]).unwrap();
+        let src = "
+LOGO_SIZE = filesize(\"size.bin\")
+logo    .incbin \"size.bin\"
+        .assert * - logo = LOGO_SIZE
+        .byte FILESIZE ( 'size.bin' ) + 1";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(info.symtab["LOGO_SIZE"].value, Some(4));
+        assert_eq!(info.bytes, vec![1, 2, 3, 4, 5]);
+        assert!(assemble_str("FOO = filesize(\"no such file\")", "src").is_err());
+        assert!(assemble_str("FOO = filesize(1)", "src").is_err());
+    }
+
+    #[test]
+    fn test_func() {
+        let src = "
+SPRITE_BASE = $2000
+        .func sprite_ptr(n) = SPRITE_BASE + n*64
+        .func lerp(a, b, t) = a + (b - a) * t / 16
+        .func two() = 2
+        .word sprite_ptr(3), sprite_ptr(two()) + 1
+        .byte lerp(0, 32, 8), lerp(>sprite_ptr(0), 64, 8)
+n       .byte n";
+        let info = assemble(source::from_str(src, "func.s"), false).unwrap();
+        assert_eq!(info.bytes, vec![0xC0, 0x20, 0x81, 0x20, 16, 48, 6]);
+        // The parameters aren't symbols.
+        assert_eq!(info.symtab["n"].value, Some(6));
+
+        assert!(assemble_str(".func f(x) = x\n.byte f(1, 2)", "func.s").is_err());
+        assert!(assemble_str(".func f(x) = x\n.byte f", "func.s").is_err());
+        assert!(assemble_str(".func f(x) = x\n.func f(y) = y", "func.s").is_err());
+        assert!(assemble_str(".func f(x, x) = x", "func.s").is_err());
+        assert!(assemble_str(".byte f(1)\n.func f(x) = x", "func.s").is_err());
+    }
+
+    #[test]
+    fn test_set() {
+        let src = "
+        .set X, 1
+        .byte X
+        .set X, 2
+        .byte X
+        .redef X, X * 3
+        .byte X";
+        let info = assemble(source::from_str(src, "set.s"), false).unwrap();
+        assert_eq!(info.symtab["X"].value, Some(6));
+        assert_eq!(info.bytes, vec![1, 2, 6]);
+        assert_eq!(info.symtab["X"].defined_at().unwrap().pos(), "set.s:2:14");
+
+        assert!(assemble_str(".set X, 1\nX = 2", "set.s").is_err());
+        assert!(assemble_str("X = 1\n.set X, 2", "set.s").is_err());
+        assert!(assemble_str("X .byte 0\n.set X, 2", "set.s").is_err());
+        assert!(assemble_str(".set 1, 2", "set.s").is_err());
+        assert!(assemble_str(".set X", "set.s").is_err());
+    }
+
+    #[test]
+    fn test_res() {
+        let src = "
+        .org $1000
+        .byte 1
+buf     .res 4
+after   .byte 2
+        .space 2
+        .byte 3";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(info.symtab["buf"].value, Some(0x1001));
+        assert_eq!(info.symtab["after"].value, Some(0x1005));
+        assert_eq!(info.bytes, vec![1, 2, 3]);
+        assert_eq!(
+            info.segments,
+            vec![(0x1000, vec![1]), (0x1005, vec![2]), (0x1008, vec![3])]
+        );
+        assert_eq!(
+            assemble_str(".ds 2\n.byte 1", "src").unwrap(),
+            vec![0, 0, 1]
+        );
+        assert!(assemble_str(".res 2, 1", "src").is_err());
+    }
+
+    #[test]
+    fn test_checkpage() {
+        let src = "
+        .org $F0
+table   .ds 20
+        .checkpage table
+fits    .ds 20
+        .checkpage fits
+        .checkpage *";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(info.warnings.len(), 1);
+        assert!(info.warnings[0].starts_with("src:4:"));
+        assert!(info.warnings[0].contains("00F0-0103"));
+        assert!(assemble_str(".checkpage *+1", "src").is_err());
+    }
+
+    #[test]
+    fn test_pstr() {
+        let src = "
+foo     .pstr \"HI\"
+bar     .pstr ''
+foobar";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(info.bytes, vec![2, b'H', b'I', 0]);
+        assert_eq!(info.symtab["bar"].value, Some(3));
+        assert_eq!(info.symtab["foobar"].value, Some(4));
+
+        let long = format!(".pstr '{}'", "A".repeat(255));
+        assert_eq!(assemble_str(&long, "src").unwrap().len(), 256);
+        let too_long = format!(".pstr '{}'", "A".repeat(256));
+        assert!(assemble_str(&too_long, "src").is_err());
+    }
+
+    #[test]
+    fn test_shift() {
+        let src = "
+foo     .shift \"HI\"
+bar     .scrtext 'A'
+        .shift ''
+foobar  .shift 1,'BC'";
+        let info = assemble(source::from_str(src, "src"), false).unwrap();
+        assert_eq!(
+            info.bytes,
+            vec![b'H', b'I' | 0x80, b'A' | 0x80, 1, b'B', b'C' | 0x80]
+        );
+        assert_eq!(info.symtab["bar"].value, Some(2));
+        assert_eq!(info.symtab["foobar"].value, Some(3));
+    }
 }
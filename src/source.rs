@@ -1,22 +1,124 @@
 //! Source file handling.
 
 use std::{
-    cmp::{max, min},
+    cmp::{max_by_key, min_by_key},
     error::Error,
-    fs,
+    fs, io,
+    path::Path,
     rc::Rc,
 };
 
 /// Used to specify a line number.
 pub type LineNum = u32;
 
+/// The longest line accepted, in bytes; indices within a line must fit in a `u16`.
+const MAX_LINE_BYTES: usize = u16::MAX as usize;
+
+/// The end-of-file mark DOS and CP/M editors leave at the end of a text file.
+const CTRL_Z: char = '\x1A';
+
 /// Allows reading from source files.
 pub type Source = Box<dyn Iterator<Item = Rc<Line>>>;
 
+/// Reads the files named by `.inc`, `.incbin`, and `filesize`, so they needn't come from the
+/// real filesystem.
+pub trait FileResolver {
+    /// Read the whole file.
+    fn read(&self, path: &str) -> io::Result<Vec<u8>>;
+
+    /// Read the whole file, which must be UTF-8.
+    fn read_str(&self, path: &str) -> io::Result<String> {
+        String::from_utf8(self.read(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Return the size of the file in bytes.
+    fn size(&self, path: &str) -> io::Result<u64> {
+        Ok(self.read(path)?.len() as u64)
+    }
+
+    /// Return true if the file exists.
+    fn exists(&self, path: &str) -> bool {
+        self.read(path).is_ok()
+    }
+
+    /// Return a path that's the same for every way of naming the file, for `.once`.
+    fn canonical(&self, path: &str) -> String {
+        path.to_string()
+    }
+}
+
+/// The default `FileResolver`, reading the real filesystem.
+pub struct FsResolver;
+
+impl FileResolver for FsResolver {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn read_str(&self, path: &str) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+
+    fn size(&self, path: &str) -> io::Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        Path::new(path).exists()
+    }
+
+    fn canonical(&self, path: &str) -> String {
+        fs::canonicalize(path).map_or_else(|_| path.to_string(), |p| p.display().to_string())
+    }
+}
+
+/// A resolver over files held in memory, by path, for tests.
+///
+/// `.` components of a path are ignored, so a file can be named more than one way.
+#[cfg(test)]
+pub(crate) struct MemFiles(pub std::collections::HashMap<&'static str, &'static [u8]>);
+
+#[cfg(test)]
+impl FileResolver for MemFiles {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        match self.0.get(self.canonical(path).as_str()) {
+            Some(bytes) => Ok(bytes.to_vec()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn canonical(&self, path: &str) -> String {
+        Vec::from_iter(path.split('/').filter(|part| *part != ".")).join("/")
+    }
+}
+
 /// Construct a source from a file.
+///
+/// The file must be UTF-8; a leading byte order mark is skipped.
 pub fn from_file(path: &str) -> Result<Source, Box<dyn Error>> {
-    let text = fs::read_to_string(path)?;
-    Ok(from_str(&text, path))
+    Ok(from_resolver(path, &FsResolver)?)
+}
+
+/// Construct a source from a file read by the resolver, as `from_file` does.
+pub fn from_resolver(path: &str, resolver: &dyn FileResolver) -> io::Result<Source> {
+    let text = resolver.read_str(path)?;
+    Ok(from_str(
+        text.strip_prefix('\u{feff}').unwrap_or(&text),
+        path,
+    ))
+}
+
+/// Resolve a path named in the file at `from_path`, as by `.inc`: a relative path is looked
+/// for next to that file first, then in the current directory.
+pub fn resolve_path(path: &str, from_path: &str, resolver: &dyn FileResolver) -> String {
+    if let Some(dir) = Path::new(from_path).parent() {
+        let beside = dir.join(path).to_string_lossy().into_owned();
+        if resolver.exists(&beside) {
+            return beside;
+        }
+    }
+    path.to_string()
 }
 
 /// Construct a fake-o source from a single string.
@@ -27,15 +129,20 @@ pub fn from_str(s: &str, path: &str) -> Source {
 /// Source from a parent string.
 struct StrSrc {
     lines: Vec<String>,
-    path: String,
+    path: Rc<str>,
     line_num: LineNum,
 }
 
 impl StrSrc {
     pub fn new(src: &str, path: &str, line_num: LineNum) -> Self {
+        // A trailing Ctrl-Z marks the end of the file, rather than being part of it.
+        let src = match src.trim_end_matches(['\r', '\n']).strip_suffix(CTRL_Z) {
+            Some(text) => text.trim_end_matches(CTRL_Z),
+            None => src,
+        };
         Self {
             lines: Vec::from_iter(src.lines().rev().map(|line| line.to_string())),
-            path: path.to_string(),
+            path: Rc::from(path),
             line_num,
         }
     }
@@ -45,16 +152,51 @@ impl Iterator for StrSrc {
     type Item = Rc<Line>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(text) = self.lines.pop() {
+        if let Some(mut text) = self.lines.pop() {
             let line_num = self.line_num;
             self.line_num += 1;
-            Some(Rc::new(Line::new(&text, &self.path, line_num)))
+            // A trailing '\' continues the line onto the next one; the joined line keeps the
+            // first one's number.
+            while continues(&text) {
+                let Some(next) = self.lines.pop() else {
+                    break;
+                };
+                text.truncate(text.trim_end().len() - 1);
+                text.push(' ');
+                text.push_str(&next);
+                self.line_num += 1;
+            }
+            Some(Rc::new(Line::with_path(text, self.path.clone(), line_num)))
         } else {
             None
         }
     }
 }
 
+/// Return true if the line ends in a `\` continuing it onto the next one: a `\` counts only
+/// as the last thing on the line outside any string or comment.
+fn continues(text: &str) -> bool {
+    let mut chars = text.chars().peekable();
+    let mut quote = None;
+    while let Some(c) = chars.next() {
+        if let Some(q) = quote {
+            if c == '\\' {
+                chars.next();
+            } else if c == q {
+                quote = None;
+            }
+        } else {
+            match c {
+                '\'' | '"' => quote = Some(c),
+                ';' => return false,
+                '/' if matches!(chars.peek(), Some('/' | '*')) => return false,
+                _ => (),
+            }
+        }
+    }
+    quote.is_none() && text.trim_end().ends_with('\\')
+}
+
 /// A stack of inputs: used as the main input for assemble().
 pub struct SrcStack {
     sources: Vec<Source>,
@@ -71,6 +213,11 @@ impl SrcStack {
     pub fn push(&mut self, src: Source) {
         self.sources.push(src);
     }
+
+    /// Drop the rest of the current source, going back to the one that included it.
+    pub fn skip_rest(&mut self) {
+        self.sources.pop();
+    }
 }
 
 impl Iterator for SrcStack {
@@ -96,16 +243,25 @@ impl Iterator for SrcStack {
 #[derive(PartialEq, Debug, Eq, Hash)]
 pub struct Line {
     pub text: String,
-    pub path: String,
+    /// Shared by every line from the same file.
+    pub path: Rc<str>,
     pub line_num: LineNum,
+    /// For a line expanded from a macro, the line of the macro's body it came from.
+    pub origin: Option<Rc<Line>>,
 }
 
 impl Line {
     pub fn new(text: &str, path: &str, line_num: LineNum) -> Self {
+        Self::with_path(text.to_string(), Rc::from(path), line_num)
+    }
+
+    /// Construct a line without copying its text or path.
+    pub fn with_path(text: String, path: Rc<str>, line_num: LineNum) -> Self {
         Self {
-            text: text.to_string(),
-            path: path.to_string(),
+            text,
+            path,
             line_num,
+            origin: None,
         }
     }
 
@@ -121,6 +277,29 @@ impl Line {
     pub fn err<T>(&self, msg: &str) -> Result<T, String> {
         Err(format!("{}: {}", self.pos(), msg))
     }
+
+    /// Check the line looks like text, rather than part of a binary file given as source:
+    /// it mustn't be too long, or hold control characters besides tab, carriage return, and
+    /// form feed.
+    pub fn check_text(&self) -> Result<(), String> {
+        if let Some(c) = self
+            .text
+            .chars()
+            .find(|c| c.is_control() && !matches!(c, '\t' | '\r' | '\x0C'))
+        {
+            return self.err(&format!(
+                "input does not look like text source (control character U+{:04X})",
+                c as u32
+            ));
+        }
+        if self.text.len() > MAX_LINE_BYTES {
+            return self.err(&format!(
+                "input does not look like text source (line longer than {} bytes)",
+                MAX_LINE_BYTES
+            ));
+        }
+        Ok(())
+    }
 }
 
 /// A slice within a given line.
@@ -135,16 +314,19 @@ pub struct LineSlice {
 
 impl LineSlice {
     pub fn new(line: Rc<Line>, start_char: u16, end_char: u16) -> Self {
-        let (start, _) = line
-            .text
-            .char_indices()
-            .nth(start_char as usize)
-            .unwrap_or((line.text.len(), ' '));
-        let (end, _) = line
-            .text
-            .char_indices()
-            .nth(end_char as usize)
-            .unwrap_or((line.text.len(), ' '));
+        // Find both byte indices in one walk over the line, where possible.
+        let len = line.text.len();
+        let mut indices = line.text.char_indices().map(|(i, _)| i);
+        let start = indices.nth(start_char as usize).unwrap_or(len);
+        let end = match end_char.checked_sub(start_char) {
+            Some(0) => start,
+            Some(n) => indices.nth(n as usize - 1).unwrap_or(len),
+            None => line
+                .text
+                .char_indices()
+                .nth(end_char as usize)
+                .map_or(len, |(i, _)| i),
+        };
 
         Self {
             line,
@@ -155,20 +337,52 @@ impl LineSlice {
         }
     }
 
+    /// Construct a slice whose byte indices in the line are already known, which saves
+    /// walking the line to find them.
+    pub(crate) fn with_indices(
+        line: Rc<Line>,
+        (start_char, end_char): (u16, u16),
+        (start_index, end_index): (u16, u16),
+    ) -> Self {
+        Self {
+            line,
+            start_char,
+            end_char,
+            start_index,
+            end_index,
+        }
+    }
+
     /// Construct a new line_slice with another; the lowest starting and highest ending positions
     /// are used.
     pub fn join(&self, other: &LineSlice) -> Self {
         assert_eq!(&self.line, &other.line);
-        Self::new(
+        let first = min_by_key(self, other, |s| s.start_char);
+        let last = max_by_key(self, other, |s| s.end_char);
+        Self::with_indices(
             self.line.clone(),
-            min(self.start_char, other.start_char),
-            max(self.end_char, other.end_char),
+            (first.start_char, last.end_char),
+            (first.start_index, last.end_index),
         )
     }
 
     /// Return a cloned LineSlice, but with a new ending position.
     pub fn with_end(&self, end_char: u16) -> Self {
-        Self::new(self.line.clone(), self.start_char, end_char)
+        let Some(n) = end_char.checked_sub(self.start_char) else {
+            return Self::new(self.line.clone(), self.start_char, end_char);
+        };
+        // Only the part of the line after the start needs walking.
+        let rest = &self.line.text[self.start_index as usize..];
+        let end_index = rest
+            .char_indices()
+            .nth(n as usize)
+            .map_or(rest.len(), |(i, _)| i)
+            + self.start_index as usize;
+        Self::with_indices(
+            self.line.clone(),
+            (self.start_char, end_char),
+            (self.start_index, end_index as u16),
+        )
     }
 
     /// Return a string representing the position in the line.
@@ -184,9 +398,28 @@ impl LineSlice {
         )
     }
 
-    /// Construct an error message using this slice's pos() as a header.
+    /// Construct an error message using this slice's pos() as a header, followed by the
+    /// caret_string().
     pub fn err<T>(&self, msg: &str) -> Result<T, String> {
-        Err(format!("{}: {}", self.pos(), msg))
+        Err(format!("{}: {}\n{}", self.pos(), msg, self.caret_string()))
+    }
+
+    /// Return the complete source line, then a line with `^` markers under this slice.
+    ///
+    /// Tabs before the slice are kept, so the markers line up however wide they're shown.
+    pub fn caret_string(&self) -> String {
+        let mut s = format!("{}\n", self.line_text().trim_end());
+        for c in self.line_text().chars().take(self.start_char as usize) {
+            s.push(if c == '\t' { '\t' } else { ' ' });
+        }
+        let width = self.end_char.saturating_sub(self.start_char).max(1);
+        s.push_str(&"^".repeat(width as usize));
+        s
+    }
+
+    /// Return the underlying line.
+    pub fn line(&self) -> &Rc<Line> {
+        &self.line
     }
 
     /// Return the underlying path.
@@ -255,6 +488,55 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_continuation() {
+        let src = from_str(".byte 1,2,3, \\\n  4,5,\\  \n6\nnop\n", "cont.s");
+        assert_eq!(
+            Vec::from_iter(src),
+            vec![
+                Rc::new(Line::new(".byte 1,2,3,    4,5, 6", "cont.s", 1)),
+                Rc::new(Line::new("nop", "cont.s", 4)),
+            ]
+        );
+        assert_eq!(
+            crate::assemble_str("        .byte 1,2,3, \\\n        4,5,6", "cont.s"),
+            Ok(vec![1, 2, 3, 4, 5, 6])
+        );
+
+        // Not in a comment or a string.
+        let src = from_str(
+            "nop ; a path: C:\\\nnop // \\\n.byte '\\\\', \\\n0\n.byte \"\\\n",
+            "cont.s",
+        );
+        assert_eq!(
+            Vec::from_iter(src),
+            vec![
+                Rc::new(Line::new("nop ; a path: C:\\", "cont.s", 1)),
+                Rc::new(Line::new("nop // \\", "cont.s", 2)),
+                Rc::new(Line::new(".byte '\\\\',  0", "cont.s", 3)),
+                Rc::new(Line::new(".byte \"\\", "cont.s", 5)),
+            ]
+        );
+        assert_eq!(
+            crate::assemble_str("        nop ; C:\\\n        nop", "cont.s"),
+            Ok(vec![0xEA, 0xEA])
+        );
+    }
+
+    #[test]
+    fn test_caret_string() {
+        let line = Rc::new(Line::new("\tlda foo,x  ", "caret.s", 3));
+        let foo = LineSlice::new(line.clone(), 5, 8);
+        assert_eq!(foo.caret_string(), "\tlda foo,x\n\t    ^^^");
+        assert!(Rc::ptr_eq(foo.line(), &line));
+        assert_eq!(
+            foo.err::<()>("'foo' undefined"),
+            Err("caret.s:3:6: 'foo' undefined\n\tlda foo,x\n\t    ^^^".to_string())
+        );
+        let end = LineSlice::new(line, 12, 12);
+        assert_eq!(end.caret_string(), "\tlda foo,x\n\t           ^");
+    }
+
     #[test]
     fn test_line_slice() {
         let foobar = Rc::new(Line::new("foobar", "foobar", 1));
@@ -271,6 +553,11 @@ mod tests {
         assert_eq!(none.text(), "");
         assert_eq!(all.text(), "foobar");
         assert_eq!(end.text(), "");
+
+        let wide = Rc::new(Line::new("é'ü' ß", "wide", 1));
+        assert_eq!(LineSlice::new(wide.clone(), 1, 4).text(), "'ü'");
+        assert_eq!(LineSlice::new(wide.clone(), 5, 9).text(), "ß");
+        assert_eq!(LineSlice::new(wide.clone(), 8, 9).text(), "");
     }
 
     #[test]
@@ -12,6 +12,17 @@ pub struct Symbol {
     pub defined_at: Option<Rc<LineSlice>>,
     pub comment: Option<String>,
     pub references: HashSet<Rc<LineSlice>>,
+    /// Set if the symbol was marked public with `.global`.
+    pub exported: bool,
+    /// Set if the symbol was defined by `.set`, so it can be `.set` again.
+    pub mutable: bool,
+    /// Set if the symbol is an address label, rather than defined with `=` or `.set`.
+    pub label: bool,
+    /// Set if the symbol was defined as a negative number, so it evaluates as one.
+    pub negative: bool,
+    /// Where in the first pass the symbol was defined, as from `Assembler::def_position`,
+    /// so the second pass resolves a name in a `.scope` to the symbol the first one did.
+    pub visible_from: usize,
 }
 
 impl PartialEq for Symbol {
@@ -65,9 +76,45 @@ impl Symbol {
             defined_at: None,
             references: refs,
             comment: None,
+            exported: false,
+            mutable: false,
+            label: false,
+            negative: false,
+            visible_from: 0,
         })
     }
 
+    /// Return the symbol's value as an expression sees it: a symbol defined as a negative
+    /// number is that number, as `-1` is, rather than its word (`$FFFF`).
+    pub fn expr_value(&self) -> Option<u32> {
+        let value = self.value?;
+        if self.negative {
+            Some(value as i16 as u32)
+        } else {
+            Some(value as u32)
+        }
+    }
+
+    /// Return where the symbol was defined, if it was.
+    ///
+    /// ```
+    /// let info = pop65::assemble(pop65::from_file("inc_test.s").unwrap(), false).unwrap();
+    /// let foo = &info.symtab["foo"];
+    /// assert_eq!(foo.defined_at().unwrap().pos(), "inc_test.s:3:1");
+    /// let mut refs: Vec<String> = foo.references().map(|r| r.pos()).collect();
+    /// refs.sort();
+    /// assert_eq!(refs, vec!["inc_2.s:2:17", "inc_test.s:3:1"]);
+    /// ```
+    pub fn defined_at(&self) -> Option<&Rc<LineSlice>> {
+        self.defined_at.as_ref()
+    }
+
+    /// Return every place the symbol is referenced, including its definition, in no
+    /// particular order.
+    pub fn references(&self) -> impl Iterator<Item = &Rc<LineSlice>> {
+        self.references.iter()
+    }
+
     /// Add a new reference to this symbol.
     ///
     /// Returns `true` if the reference was already in the list.
@@ -75,6 +122,24 @@ impl Symbol {
         self.references.insert(ref_slice)
     }
 
+    /// Set the value of this symbol by `.set`; error if it was defined any other way.
+    pub fn set(&mut self, value: u16, defined_at: Rc<LineSlice>) -> Result<(), String> {
+        if self.value.is_some() && !self.mutable {
+            return defined_at.err(&format!(
+                "'{}' redefined (orig. def. at {}); only a symbol defined by '.set' can be set",
+                &self.name,
+                self.defined_at.as_ref().unwrap().pos()
+            ));
+        }
+        self.value = Some(value);
+        self.mutable = true;
+        if self.defined_at.is_none() {
+            self.defined_at = Some(defined_at.clone());
+        }
+        self.add_ref(defined_at);
+        Ok(())
+    }
+
     /// Try to define the value of this symbol; error if we're redefined.
     pub fn define(&mut self, value: u16, defined_at: Rc<LineSlice>) -> Result<(), String> {
         if self.value.is_none() {